@@ -1,123 +1,719 @@
-use crate::{db::Db, Error, Roots};
-use llvm_ir_analysis::{llvm_ir::Module, ModuleAnalysis};
+use crate::{
+    crate_fs::{CrateEntry, CrateFs},
+    logging::{log_finished, Span},
+    store::GraphStore,
+    Error,
+};
+use llvm_ir_analysis::{
+    llvm_ir::{Constant, Function, Instruction, Module, Name, Operand, Terminator},
+    ModuleAnalysis,
+};
 use rayon::prelude::*;
 use rustc_demangle::demangle;
 
 use crates_index::Crate;
-use std::{io::Write, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-const BLOCKED_STRINGS: &[&str] = &["llvm.", "__rust", "rt::", "std::", "core::", "alloc::"];
+/// Symbol prefixes of the compiler runtime, allocator shims and panic machinery.
+const COMPILER_RT_PREFIXES: &[&str] = &[
+    "__rust",
+    "__rdl_",
+    "__rg_",
+    "compiler_builtins::",
+    "rust_begin_unwind",
+    "rust_eh_personality",
+];
 
-/// Extract all function calls/invocations within a bytecode file. Returns a `Vec<(String,String)>`
-/// of (caller, callee) demangled function names.
-///
-/// # Panics
-/// This function will panic if iterating the `Roots::bytecode_root` fails.
+/// Where a function comes from, see `classify_function`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum FunctionClass {
+    /// An LLVM intrinsic, such as `llvm.memcpy.p0.p0.i64`.
+    Intrinsic,
+    /// A function of the `std` crate.
+    RustStd,
+    /// A function of the `core` crate.
+    RustCore,
+    /// A function of the `alloc` crate.
+    RustAlloc,
+    /// The compiler runtime, allocator shims or panic machinery.
+    CompilerRt,
+    /// Any other function, defined by a crate or a non-Rust library.
+    User,
+}
+impl FunctionClass {
+    /// Parse the name of a class as given in a `class:` exclusion, see `is_excluded_call`.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "intrinsic" => Some(Self::Intrinsic),
+            "std" => Some(Self::RustStd),
+            "core" => Some(Self::RustCore),
+            "alloc" => Some(Self::RustAlloc),
+            "compiler-rt" => Some(Self::CompilerRt),
+            "user" => Some(Self::User),
+            _ => None,
+        }
+    }
+}
+
+/// Classify a demangled function name by where it comes from. Trait implementations, such as
+/// `<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop`, are classified by their implementing
+/// type.
+#[must_use]
+pub fn classify_function(name: &str) -> FunctionClass {
+    let path = name.trim_start_matches('<');
+
+    if path.starts_with("llvm.") {
+        FunctionClass::Intrinsic
+    } else if COMPILER_RT_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        FunctionClass::CompilerRt
+    } else if path.starts_with("std::") {
+        FunctionClass::RustStd
+    } else if path.starts_with("core::") {
+        FunctionClass::RustCore
+    } else if path.starts_with("alloc::") {
+        FunctionClass::RustAlloc
+    } else {
+        FunctionClass::User
+    }
+}
+
+/// Exclusions used by `is_excluded_call` when none are given.
+const DEFAULT_EXCLUDE_PATTERNS: &[&str] =
+    &["llvm.", "__rust", "rt::", "std::", "core::", "alloc::"];
+
+/// Prefix of an exclusion which names a `FunctionClass` rather than a pattern.
+const CLASS_EXCLUSION_PREFIX: &str = "class:";
+
+/// Returns true if either demangled function name matches a single exclusion. An exclusion of
+/// the form `class:<class>`, such as `class:compiler-rt`, matches functions of that class as
+/// classified by `classify_function`. Any other exclusion is a pattern which matches anywhere in
+/// a name. A `class:` exclusion which names no class matches nothing.
+fn matches_exclusion(caller: &str, callee: &str, exclusion: &str) -> bool {
+    match exclusion.strip_prefix(CLASS_EXCLUSION_PREFIX) {
+        Some(class) => FunctionClass::from_name(class).map_or(false, |class| {
+            classify_function(caller) == class || classify_function(callee) == class
+        }),
+        None => caller.contains(exclusion) || callee.contains(exclusion),
+    }
+}
+
+/// Returns true if a call between two demangled functions should be skipped, as either name
+/// matches any of the `exclude` exclusions, or of `DEFAULT_EXCLUDE_PATTERNS` if none are given.
+/// See `matches_exclusion` for how exclusions match. The default patterns match anywhere in a
+/// name, so calls to or from trait implementations involving the standard library, such as
+/// `<T as core::fmt::Debug>::fmt`, are excluded by default.
+fn is_excluded_call(caller: &str, callee: &str, exclude: &[String]) -> bool {
+    if exclude.is_empty() {
+        DEFAULT_EXCLUDE_PATTERNS
+            .iter()
+            .any(|e| matches_exclusion(caller, callee, e))
+    } else {
+        exclude.iter().any(|e| matches_exclusion(caller, callee, e))
+    }
+}
+
+/// Demangle a Rust symbol. With `include_hash` the trailing hash of legacy symbols is kept, such
+/// as `core::fmt::write::h0123456789abcdef`, otherwise it is stripped for display and name matching.
+/// Symbols which are not Rust symbols are returned unchanged.
+#[must_use]
+pub fn demangle_symbol(name: &str, include_hash: bool) -> String {
+    if include_hash {
+        demangle(name).to_string()
+    } else {
+        format!("{:#}", demangle(name))
+    }
+}
+
+/// How a call from the caller reaches the callee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum CallKind {
+    /// The callee is called directly by its symbol.
+    Direct,
+    /// The call is made through a function pointer, and the callee is one of the functions with a
+    /// matching type.
+    Indirect,
+}
+impl CallKind {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CallKind::Direct => "direct",
+            CallKind::Indirect => "indirect",
+        }
+    }
+}
+
+/// A single call from one function to another, extracted from a crates bytecode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct CallRecord {
+    /// The raw, mangled symbol of the calling function.
+    pub caller: String,
+    /// The raw, mangled symbol of the called function.
+    pub callee: String,
+    /// The demangled name of the calling function, without its hash.
+    pub caller_demangled: String,
+    /// The demangled name of the called function, without its hash.
+    pub callee_demangled: String,
+    /// The name of the LLVM module the call was found in.
+    pub module: String,
+    /// Whether the call is direct or through a function pointer.
+    pub kind: CallKind,
+}
+
+impl CallRecord {
+    /// The name of the crate the called function belongs to, taken from the first path segment
+    /// of its demangled name, or `NONE` if it has no path.
+    #[must_use]
+    pub fn callee_crate(&self) -> &str {
+        self.callee_demangled
+            .split_once("::")
+            .unwrap_or(("NONE", ""))
+            .0
+    }
+}
+
+/// Returns the global symbol a constant refers to, looking through pointer casts.
+fn global_name(constant: &Constant) -> Option<&str> {
+    match constant {
+        Constant::GlobalReference {
+            name: Name::Name(name),
+            ..
+        } => Some(name.as_str()),
+        Constant::BitCast(cast) => global_name(&cast.operand),
+        _ => None,
+    }
+}
+
+/// Collect all `(caller, callee)` symbol pairs within a module where the callee is called directly
+/// rather than through a function pointer.
+fn direct_calls(module: &Module) -> HashSet<(&str, &str)> {
+    let mut calls = HashSet::new();
+
+    for func in &module.functions {
+        for bb in &func.basic_blocks {
+            let targets = bb
+                .instrs
+                .iter()
+                .filter_map(|i| match i {
+                    Instruction::Call(call) => call.function.as_ref().right(),
+                    _ => None,
+                })
+                .chain(match &bb.term {
+                    Terminator::Invoke(invoke) => invoke.function.as_ref().right(),
+                    _ => None,
+                });
+
+            for target in targets {
+                if let Operand::ConstantOperand(constant) = target {
+                    if let Some(callee) = global_name(constant) {
+                        calls.insert((func.name.as_str(), callee));
+                    }
+                }
+            }
+        }
+    }
+
+    calls
+}
+
+/// Extensions of the LLVM module files which are analyzed: bitcode emitted with `--emit=llvm-bc`
+/// and textual IR emitted with `--emit=llvm-ir`.
+pub const MODULE_EXTENSIONS: &[&str] = &["bc", "ll"];
+
+/// Returns true if the path is an LLVM module by its extension, see `MODULE_EXTENSIONS`.
+#[must_use]
+pub fn is_module_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| MODULE_EXTENSIONS.contains(&ext))
+}
+
+/// Total size in bytes of the LLVM modules directly within a directory, or 0 if it can not be read.
+#[must_use]
+pub fn module_bytes(dir: &Path) -> u64 {
+    std::fs::read_dir(dir).map_or(0, |entries| {
+        entries
+            .filter_map(Result::ok)
+            .filter(|e| is_module_path(&e.path()))
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum()
+    })
+}
+
+/// Load an LLVM module, parsing it as textual IR if it has the `.ll` extension and as bitcode
+/// otherwise.
 ///
-/// This function will panic if an LLVM parsing error occurs while parsing the bytecode.
 /// # Errors
-/// TODO: Failure cases currently panic and should be moved to errors.
-#[allow(clippy::unnecessary_wraps)]
-pub fn extract_calls<P: AsRef<Path>>(crate_bc_dir: P) -> Result<Vec<(String, String)>, Error> {
-    let mut calls = Vec::<(String, String)>::new();
+/// Returns `Error::LLVMError` if the module fails to parse.
+pub fn load_module(path: &Path) -> Result<Module, Error> {
+    if path.extension().map_or(false, |ext| ext == "ll") {
+        Module::from_ir_path(path)
+    } else {
+        Module::from_bc_path(path)
+    }
+    .map_err(Error::LLVMError)
+}
+
+/// Load every bytecode module within a directory, either bitcode or textual IR. Files which fail to parse are collected along
+/// with their error rather than aborting the load, so a corrupt file only loses that module. If
+/// the directory itself can not be read, it is returned as the only failure.
+#[must_use]
+pub fn load_modules_from_dir<P: AsRef<Path>>(dir: P) -> (Vec<Module>, Vec<(PathBuf, Error)>) {
+    let mut modules = Vec::new();
+    let mut failures = Vec::new();
+
+    let entries = match std::fs::read_dir(dir.as_ref()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            failures.push((dir.as_ref().to_path_buf(), e.into()));
+            return (modules, failures);
+        }
+    };
 
-    for bc_entry in std::fs::read_dir(crate_bc_dir.as_ref())
-        .unwrap()
+    for path in entries
         .filter_map(Result::ok)
-        .filter(|e| e.path().extension().is_some() && e.path().extension().unwrap() == "bc")
+        .map(|e| e.path())
+        .filter(|p| is_module_path(p))
     {
-        let bc_path = bc_entry.path();
+        match load_module(&path) {
+            Ok(module) => modules.push(module),
+            Err(e) => failures.push((path, e)),
+        }
+    }
 
-        let module = Module::from_bc_path(&bc_path)
-            .map_err(Error::LLVMError)
-            .unwrap();
-        let analysis = ModuleAnalysis::new(&module);
+    (modules, failures)
+}
+
+/// Extract all function calls/invocations within a bytecode file. Returns a `Vec<CallRecord>`
+/// describing each call. Calls are filtered with `exclude` as in `is_excluded_call`, so by default
+/// only calls between user functions are kept.
+///
+/// Modules are loaded with `load_modules_from_dir`; any which fail to load are logged and skipped,
+/// and calls are extracted from the rest.
+///
+/// # Errors
+/// Currently always succeeds, failures of individual modules are logged.
+#[allow(clippy::unnecessary_wraps)]
+pub fn extract_call_records<P: AsRef<Path>>(
+    crate_bc_dir: P,
+    exclude: &[String],
+) -> Result<Vec<CallRecord>, Error> {
+    let (modules, failures) = load_modules_from_dir(&crate_bc_dir);
+    for (path, e) in failures {
+        log::warn!("Failed loading {}: {}", path.display(), e);
+    }
+
+    Ok(module_call_records(&modules, exclude))
+}
+
+/// Extract all function calls within a bytecode directory as `extract_call_records` does, but
+/// only if every module loads, so the calls are known to be complete.
+///
+/// # Errors
+/// Returns `Error::IncompleteBytecode` if the directory can not be read or any module in it fails
+/// to load. Each failure is logged.
+pub fn extract_complete_call_records<P: AsRef<Path>>(
+    crate_bc_dir: P,
+    exclude: &[String],
+) -> Result<Vec<CallRecord>, Error> {
+    let (modules, failures) = load_modules_from_dir(&crate_bc_dir);
+    if !failures.is_empty() {
+        for (path, e) in &failures {
+            log::error!("Failed loading {}: {}", path.display(), e);
+        }
+        return Err(Error::IncompleteBytecode(
+            crate_bc_dir.as_ref().to_path_buf(),
+            failures.len(),
+        ));
+    }
+
+    Ok(module_call_records(&modules, exclude))
+}
+
+/// Collect the calls of every loaded module, filtered with `exclude` as in `is_excluded_call`.
+fn module_call_records(modules: &[Module], exclude: &[String]) -> Vec<CallRecord> {
+    let mut calls = Vec::<CallRecord>::new();
+
+    for module in modules {
+        let analysis = ModuleAnalysis::new(module);
+        let direct = direct_calls(module);
 
         let graph = analysis.call_graph();
         graph.inner().all_edges().for_each(|(src_raw, dst_raw, _)| {
-            let src = format!("{:#}", demangle(src_raw));
-            let dst = format!("{:#}", demangle(dst_raw));
+            let src = demangle_symbol(src_raw, false);
+            let dst = demangle_symbol(dst_raw, false);
 
-            if !BLOCKED_STRINGS
-                .iter()
-                .any(|s| src.contains(*s) || dst.contains(*s))
-            {
-                calls.push((src, dst));
+            if !is_excluded_call(&src, &dst, exclude) {
+                let kind = if direct.contains(&(src_raw, dst_raw)) {
+                    CallKind::Direct
+                } else {
+                    CallKind::Indirect
+                };
+
+                calls.push(CallRecord {
+                    caller: src_raw.to_string(),
+                    callee: dst_raw.to_string(),
+                    caller_demangled: src,
+                    callee_demangled: dst,
+                    module: module.name.clone(),
+                    kind,
+                });
             }
         });
     }
 
-    Ok(calls)
+    calls
 }
 
-/// Extracts all calls within a  single crates bytecode. Then, perform database insertions of each
-/// call into the database.
+/// Extract all function calls/invocations within a bytecode file. Returns a `Vec<(String,String)>`
+/// of (caller, callee) demangled function names. See `extract_call_records` for the full records.
 ///
-/// # Panics
-/// This function panics if extracting the filename of a crates full name from its path fails.
+/// # Errors
+/// Currently always succeeds, failures of individual modules are logged.
+pub fn extract_calls<P: AsRef<Path>>(
+    crate_bc_dir: P,
+    exclude: &[String],
+) -> Result<Vec<(String, String)>, Error> {
+    Ok(extract_call_records(crate_bc_dir, exclude)?
+        .into_iter()
+        .map(|c| (c.caller_demangled, c.callee_demangled))
+        .collect())
+}
+
+/// Extracts all calls within a single crates bytecode, replacing any invocations already stored
+/// for that crate version. Unlike `export_all_db`, this does not skip versions which already
+/// have invocations, and is used to refresh the call graph of a recompiled crate. The stored
+/// invocations are only replaced if every module of the crate loads.
 ///
 /// # Errors
-/// Returns `painter::analysis::Error` on failure of database insertion.
-#[allow(clippy::needless_pass_by_value)]
-pub async fn export_crate_db<P: AsRef<Path>>(crate_bc_dir: P, db: Arc<Db>) -> Result<(), Error> {
-    let calls = extract_calls(&crate_bc_dir)?;
-    let crate_fullname = crate_bc_dir.as_ref().file_name().unwrap().to_str().unwrap();
+/// Returns `Error::CrateFsError` if the directory name is not in the `NAME-VER` format,
+/// `Error::IncompleteBytecode` if the directory can not be read or any module fails to load, or
+/// `painter::analysis::Error` on failure of database insertion.
+pub async fn reimport_crate_db<P: AsRef<Path>>(
+    crate_bc_dir: P,
+    db: Arc<dyn GraphStore>,
+    exclude: &[String],
+) -> Result<(), Error> {
+    let file_name = crate_bc_dir.as_ref().file_name().unwrap_or_default();
+    let entry = CrateEntry::try_from(file_name.to_string_lossy().as_ref())?;
+
+    let calls = extract_complete_call_records(&crate_bc_dir, exclude)?;
+
+    log::trace!("Reimporting: {}", entry.full_name());
+    let start = std::time::Instant::now();
+
+    db.reimport_crate((entry.name(), entry.version()), &calls)
+        .await?;
 
-    let (crate_name, crate_version) = crate_fullname.rsplit_once('-').unwrap();
+    log_finished("export", entry.name(), entry.version(), start.elapsed());
 
-    // If this crate/version has an invoke, assume its completed and bail
-    if db.has_any_invoke(crate_name, crate_version).await? {
-        log::trace!("{}-{} Exists, skipping..", crate_name, crate_version);
-        return Ok(());
+    Ok(())
+}
+
+/// Default number of extracted crates buffered between the bytecode parsers and the database
+/// writers of `export_all_db`.
+pub const DEFAULT_QUEUE_DEPTH: usize = 32;
+
+/// Default number of concurrent database writers of `export_all_db`.
+pub const DEFAULT_WRITERS: usize = 4;
+
+/// Sizing of the queue between the bytecode parsers and the database writers of `export_all_db`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportQueueConfig {
+    /// Number of extracted crates which can wait to be written. Parsers block once it is full,
+    /// so memory stays bounded when the database can not keep up.
+    pub depth: usize,
+    /// Number of crates written to the database concurrently.
+    pub writers: usize,
+}
+impl Default for ExportQueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_QUEUE_DEPTH,
+            writers: DEFAULT_WRITERS,
+        }
     }
+}
 
-    log::trace!("Importing: {}", crate_name);
+/// The calls extracted from a single crate version, waiting to be written to the database.
+struct ExportBatch {
+    name: String,
+    version: String,
+    calls: Vec<CallRecord>,
+}
 
-    for (caller, callee) in &calls {
-        let dst_crate = callee.split_once("::").unwrap_or(("NONE", "")).0;
-        db.insert_invoke(caller, callee, (crate_name, crate_version), dst_crate)
-            .await?;
+/// Iterate across all crates in the bytecode root, and export their calls. The calls of each
+/// crate are inserted in a single transaction, so an interrupted export never leaves a partially
+/// imported crate. Calls are filtered with `exclude` as in `extract_calls`.
+///
+/// Crates which were already exported are skipped before any bytecode is loaded, so an
+/// interrupted export can be resumed, unless `force` is set, in which case their invocations are
+/// replaced as in `reimport_crate_db`. A forced export leaves the stored invocations of a crate
+/// alone if any of its modules fail to load.
+///
+/// Bytecode is parsed on the rayon thread pool, and the calls of each crate are sent through a
+/// queue of `queue.depth` crates to `queue.writers` concurrent database writers. Parsing blocks
+/// while the queue is full, so it slows down to the rate the database can write at.
+///
+/// # Errors
+/// Returns `painter::analysis::Error` if the bytecode root can not be read, or on failure of a
+/// database query checking which crates were exported. Failures of individual crates are logged.
+pub async fn export_all_db<P: AsRef<Path>>(
+    bc_root: P,
+    db: Arc<dyn GraphStore>,
+    exclude: &[String],
+    force: bool,
+    queue: ExportQueueConfig,
+) -> Result<(), Error> {
+    let mut dirs = Vec::new();
+    for e in std::fs::read_dir(&bc_root)?
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+    {
+        let entry = match CrateEntry::try_from(e.file_name().to_string_lossy().as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::error!("Skipping {}: {}", e.path().display(), err);
+                continue;
+            }
+        };
+
+        // Invocations of a crate are inserted in a single transaction, so if this crate/version
+        // has an invoke its export completed and it can be skipped
+        if !force && db.has_any_invoke(entry.name(), entry.version()).await? {
+            log::trace!("{} Exists, skipping..", entry.full_name());
+            continue;
+        }
+        dirs.push((e.path(), entry));
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<ExportBatch>(queue.depth.max(1));
+    let exclude = exclude.to_vec();
+
+    let producer = tokio::task::spawn_blocking(move || {
+        dirs.par_iter().for_each(|(dir, entry)| {
+            let span = Span::enter("extract", entry.name(), entry.version());
+            // Replacing stored invocations with a partial set would lose calls, so a forced
+            // export skips crates whose modules do not all load
+            let calls = if force {
+                extract_complete_call_records(dir, &exclude)
+            } else {
+                extract_call_records(dir, &exclude)
+            };
+            let calls = match calls {
+                Ok(calls) => calls,
+                Err(e) => {
+                    log::error!("Failed extracting {}: {}", entry.full_name(), e);
+                    return;
+                }
+            };
+            span.finish();
+
+            let batch = ExportBatch {
+                name: entry.name().to_string(),
+                version: entry.version().to_string(),
+                calls,
+            };
+            // Only fails if every writer has stopped, which only happens once this is done
+            let _ = tx.blocking_send(batch);
+        });
+    });
+
+    let rx = tokio::sync::Mutex::new(rx);
+    let writers = (0..queue.writers.max(1)).map(|_| async {
+        loop {
+            // Release the receiver before writing, so other writers can take the next batch
+            let Some(batch) = rx.lock().await.recv().await else {
+                break;
+            };
+
+            let start = std::time::Instant::now();
+            let src_crate = (batch.name.as_str(), batch.version.as_str());
+            let result = if force {
+                db.reimport_crate(src_crate, &batch.calls).await
+            } else {
+                db.insert_invokes(src_crate, &batch.calls).await
+            };
+
+            match result {
+                Ok(()) => log_finished("export", &batch.name, &batch.version, start.elapsed()),
+                Err(e) => {
+                    let _span = Span::enter("export", &batch.name, &batch.version);
+                    log::error!(
+                        "Failed exporting crate {}-{}: {}",
+                        batch.name,
+                        batch.version,
+                        e
+                    );
+                }
+            }
+        }
+    });
+
+    let (producer, _) = tokio::join!(producer, futures::future::join_all(writers));
+    if let Err(e) = producer {
+        log::error!("Bytecode parsing stopped: {}", e);
     }
 
     Ok(())
 }
 
-/// Iterate across all crates in the bytecode root, and call `export_crate_db`
+/// Counts of the crates an `export_all_db` run would handle, see `plan_export_all_db`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExportPlan {
+    /// Crates with bytecode in the bytecode root.
+    pub total: usize,
+    /// Crates which already have invocations stored.
+    pub exported: usize,
+    /// Crates which would be exported.
+    pub to_export: usize,
+    /// Total size of the bytecode of the crates which would be exported.
+    pub bytecode_bytes: u64,
+}
+
+/// Count the crates `export_all_db` would export with the same `force`, without loading any
+/// bytecode or writing to the database.
 ///
-/// # Panics
-/// This function panics if there are permissions issues reading the bytecode root directory.
 /// # Errors
-/// Returns `painter::analysis::Error` on failure.
-pub async fn export_all_db<P: AsRef<Path>>(bc_root: P, db: Arc<Db>) -> Result<(), Error> {
-    let dirs: Vec<_> = std::fs::read_dir(&bc_root)
-        .unwrap()
+/// Returns `painter::analysis::Error` if the bytecode root can not be read, or on failure of a
+/// database query.
+pub async fn plan_export_all_db<P: AsRef<Path>>(
+    bc_root: P,
+    db: Arc<dyn GraphStore>,
+    force: bool,
+) -> Result<ExportPlan, Error> {
+    let mut plan = ExportPlan::default();
+
+    for crate_bc_dir in std::fs::read_dir(&bc_root)?
         .filter_map(Result::ok)
         .filter(|e| e.path().is_dir())
-        .collect();
+    {
+        let file_name = crate_bc_dir.file_name();
+        let Ok(entry) = CrateEntry::try_from(file_name.to_string_lossy().as_ref()) else {
+            continue;
+        };
 
-    let iter = dirs.iter().array_chunks::<16>();
-    for chunk in iter {
-        let tasks: Vec<_> = chunk
-            .into_iter()
-            .map(|c| export_crate_db(c.path(), db.clone()))
-            .collect();
+        plan.total += 1;
+        if db.has_any_invoke(entry.name(), entry.version()).await? {
+            plan.exported += 1;
+            if !force {
+                continue;
+            }
+        }
 
-        futures::future::join_all(tasks).await;
+        plan.to_export += 1;
+        plan.bytecode_bytes += module_bytes(&crate_bc_dir.path());
     }
 
-    //for crate_bc_dir in dirs {
-    //    ;
-    //}
+    Ok(plan)
+}
 
-    Ok(())
+/// A function within an exported call graph.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct CallGraphNode {
+    /// The raw, mangled symbol of the function.
+    pub symbol: String,
+    /// The demangled name of the function, without its hash.
+    pub name: String,
 }
 
+/// The call graph of a single crate version, as written by `export_all_json`.
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CallGraphDocument {
+    pub name: String,
+    pub version: String,
+    pub nodes: Vec<CallGraphNode>,
+    pub edges: Vec<CallRecord>,
+}
+impl CallGraphDocument {
+    /// Build the document of a crate version from its extracted calls, collecting every function
+    /// appearing on either side of a call as a node.
+    #[must_use]
+    pub fn new(name: &str, version: &str, edges: Vec<CallRecord>) -> Self {
+        let mut seen = HashSet::new();
+        let mut nodes = Vec::new();
+        for call in &edges {
+            for (symbol, name) in [
+                (&call.caller, &call.caller_demangled),
+                (&call.callee, &call.callee_demangled),
+            ] {
+                if seen.insert(symbol.as_str()) {
+                    nodes.push(CallGraphNode {
+                        symbol: symbol.clone(),
+                        name: name.clone(),
+                    });
+                }
+            }
+        }
+
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            nodes,
+            edges,
+        }
+    }
+}
+
+/// Iterate across all crates in the bytecode root, writing the call graph of each to `out` as a
+/// `CallGraphDocument`. Documents are written one per line as each crate is processed, so the
+/// output is JSON Lines rather than a single JSON document.
+///
+/// Directories whose name is not in the `NAME-VER` format are logged and skipped.
+///
+/// # Errors
+/// Returns `painter::analysis::Error` if the bytecode root can not be read, or on failure to
+/// write the output.
+pub fn export_all_json<P1: AsRef<Path>, P2: AsRef<Path>>(
+    bc_root: P1,
+    out: P2,
+    exclude: &[String],
+) -> Result<(), Error> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+
+    for crate_bc_dir in std::fs::read_dir(&bc_root)?
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+    {
+        let file_name = crate_bc_dir.file_name();
+        let entry = match CrateEntry::try_from(file_name.to_string_lossy().as_ref()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("Skipping {}: {}", crate_bc_dir.path().display(), e);
+                continue;
+            }
+        };
+
+        log::trace!("Exporting: {}", entry.full_name());
+
+        let calls = extract_call_records(crate_bc_dir.path(), exclude)?;
+        let document = CallGraphDocument::new(entry.name(), entry.version(), calls);
+
+        serde_json::to_writer(&mut file, &document).map_err(std::io::Error::from)?;
+        writeln!(file)?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CountUnsafeEntry {
     pub safe: u32,
     pub unsafe_: u32,
 }
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CountUnsafeResult {
     pub functions: CountUnsafeEntry,
     pub exprs: CountUnsafeEntry,
@@ -143,106 +739,450 @@ impl CountUnsafeResult {
             + self.item_traits.unsafe_
             + self.methods.unsafe_
     }
+
+    /// Add the counts of another result to this one.
+    pub fn add(&mut self, other: &CountUnsafeResult) {
+        for (entry, other) in [
+            (&mut self.functions, &other.functions),
+            (&mut self.exprs, &other.exprs),
+            (&mut self.item_impls, &other.item_impls),
+            (&mut self.item_traits, &other.item_traits),
+            (&mut self.methods, &other.methods),
+        ] {
+            entry.safe += other.safe;
+            entry.unsafe_ += other.unsafe_;
+        }
+    }
 }
 
-pub(crate) async fn count_unsafe_crate_extract(
-    c: Crate,
-    roots: Roots,
-    db: Arc<Db>,
-) -> Result<(), Error> {
-    let compressed_root = &roots.compressed_root;
-    let sources_root = &roots.sources_root;
+/// Demangled path prefixes of functions which can only be called from an unsafe context. Calls to
+/// these, when not inlined, are counted as unsafe operations by `count_unsafe_ir`.
+///
+/// Unsafe functions which the standard library calls on behalf of safe code, such as drop glue,
+/// the global allocator and `ptr::read`/`write`/`copy` used by `Vec`, `Box` and `String`, are
+/// instantiated into nearly every module and are deliberately not listed.
+pub const UNSAFE_FUNCTIONS: &[&str] = &[
+    "core::intrinsics::",
+    "core::slice::raw::from_raw_parts",
+    "core::str::converts::from_utf8_unchecked",
+    "core::hint::unreachable_unchecked",
+    "core::mem::transmute",
+    "core::mem::zeroed",
+    "llvm.assume",
+];
 
-    for v in c.versions() {
-        let crate_fullname = format!("{}-{}", v.name(), v.version());
-        let crate_path = compressed_root.join(format!("{}.crate", &crate_fullname));
-
-        // Lets work off the tgz for now, since we cant extract
-        // TODO: this needs to be unified to a file driver
-        if std::fs::metadata(&crate_path).is_ok() {
-            let extracted_path = sources_root.join(&crate_fullname);
-            let tar_gz = std::fs::File::open(&crate_path).unwrap();
-            let tar = flate2::read::GzDecoder::new(tar_gz);
-            let mut archive = tar::Archive::new(tar);
-            if archive.unpack(sources_root).is_ok() {
-                log::trace!("Extracted {}", &crate_fullname);
-
-                // Run our count
-                let output = std::process::Command::new("count-unsafe")
-                    .args([&extracted_path])
-                    .output()
-                    .unwrap();
-                if output.status.success() {
-                    let raw_json = std::str::from_utf8(&output.stdout).unwrap();
-                    log::trace!("{}", &raw_json);
-
-                    let unsafe_result: CountUnsafeResult = serde_json::from_str(raw_json).unwrap();
-                    if unsafe_result.has_unsafe() {
-                        log::debug!("{} unsafe", &crate_fullname);
-                        db.set_unsafe(v.name(), v.version(), &unsafe_result).await;
-                        //.unwrap();
+/// Symbol of the section rustc embeds gdb pretty printer scripts in. The `main` shim of every
+/// binary does a volatile load of it so it is kept by the linker.
+const GDB_SCRIPTS_SECTION: &str = "__rustc_debug_gdb_scripts_section__";
+
+/// Returns true if a load address is the `GDB_SCRIPTS_SECTION`, directly or through a constant
+/// `getelementptr`.
+fn is_gdb_scripts_section(address: &Operand) -> bool {
+    let Operand::ConstantOperand(constant) = address else {
+        return false;
+    };
+    let constant: &Constant = match &**constant {
+        Constant::GetElementPtr(gep) => &gep.address,
+        constant => constant,
+    };
+
+    global_name(constant) == Some(GDB_SCRIPTS_SECTION)
+}
+
+/// Names of the blocks of a function which are only entered as the default of a `switch` or as the
+/// normal return of an `invoke`. rustc ends these with `unreachable` for the default arm of an
+/// exhaustive `match` and after calls which never return, so they do not indicate unsafe code.
+fn implicitly_unreachable_blocks(func: &Function) -> HashSet<&Name> {
+    let mut implicit = HashSet::new();
+    let mut explicit = HashSet::new();
+
+    for bb in &func.basic_blocks {
+        match &bb.term {
+            Terminator::Switch(switch) => {
+                implicit.insert(&switch.default_dest);
+                explicit.extend(switch.dests.iter().map(|(_, dest)| dest));
+            }
+            Terminator::Invoke(invoke) => {
+                implicit.insert(&invoke.return_label);
+                explicit.insert(&invoke.exception_label);
+            }
+            Terminator::Br(br) => {
+                explicit.insert(&br.dest);
+            }
+            Terminator::CondBr(br) => {
+                explicit.insert(&br.true_dest);
+                explicit.insert(&br.false_dest);
+            }
+            Terminator::IndirectBr(br) => explicit.extend(br.possible_dests.iter()),
+            Terminator::CallBr(callbr) => {
+                explicit.insert(&callbr.return_label);
+                explicit.extend(callbr.other_labels.iter());
+            }
+            Terminator::CatchRet(ret) => {
+                explicit.insert(&ret.successor);
+            }
+            Terminator::CleanupRet(ret) => explicit.extend(ret.unwind_dest.iter()),
+            Terminator::CatchSwitch(switch) => {
+                explicit.extend(switch.catch_handlers.iter());
+                explicit.extend(switch.default_unwind_dest.iter());
+            }
+            Terminator::Ret(_) | Terminator::Resume(_) | Terminator::Unreachable(_) => {}
+        }
+    }
+
+    implicit.retain(|name| !explicit.contains(name));
+    implicit
+}
+
+/// Returns true if a call target is inline assembly, given as `None`, or a function in
+/// `UNSAFE_FUNCTIONS`.
+fn is_unsafe_call(function: Option<&Operand>) -> bool {
+    match function {
+        None => true,
+        Some(Operand::ConstantOperand(constant)) => global_name(constant).map_or(false, |name| {
+            let demangled = demangle_symbol(name, false);
+            UNSAFE_FUNCTIONS
+                .iter()
+                .any(|prefix| demangled.starts_with(prefix))
+        }),
+        Some(_) => false,
+    }
+}
+
+/// Heuristically count unsafe usage within a module from its IR, without the crate sources. A
+/// function defined in the module is counted as unsafe if it contains any of:
+///
+/// - volatile loads or stores, as emitted for `ptr::read_volatile` and `ptr::write_volatile`,
+///   other than the load of the `GDB_SCRIPTS_SECTION` in the `main` shim
+/// - inline assembly
+/// - calls to a function in `UNSAFE_FUNCTIONS`
+/// - an `unreachable` terminator, as left by an inlined `unreachable_unchecked`. Those following
+///   a call or in `implicitly_unreachable_blocks` are not counted, as rustc emits them after every
+///   diverging call such as a panic and for the default of every exhaustive `match`.
+///
+/// Only functions classified as `FunctionClass::User` are counted, so monomorphized standard
+/// library code instantiated into the module is not attributed to the crate.
+///
+/// Each such operation is counted in `exprs.unsafe_`, and each function in either `functions.safe`
+/// or `functions.unsafe_`. Impls, traits and methods do not exist at the IR level and are left
+/// at zero. The counts are an approximation meant to corroborate those of `count-unsafe`; much
+/// unsafe code, such as dereferencing raw pointers, is indistinguishable from safe code once
+/// compiled.
+#[must_use]
+pub fn count_unsafe_ir(module: &Module) -> CountUnsafeResult {
+    let mut result = CountUnsafeResult::default();
+
+    for func in &module.functions {
+        if classify_function(&demangle_symbol(&func.name, false)) != FunctionClass::User {
+            continue;
+        }
+
+        let implicitly_unreachable = implicitly_unreachable_blocks(func);
+        let mut unsafe_ops = 0;
+
+        for bb in &func.basic_blocks {
+            unsafe_ops += bb
+                .instrs
+                .iter()
+                .filter(|i| match i {
+                    Instruction::Load(load) => {
+                        load.volatile && !is_gdb_scripts_section(&load.address)
                     }
+                    Instruction::Store(store) => store.volatile,
+                    Instruction::Call(call) => is_unsafe_call(call.function.as_ref().right()),
+                    _ => false,
+                })
+                .count();
 
-                    // Finally delete
-                    //std::fs::remove_dir_all(extracted_path).unwrap();
-                    log::trace!("Deleted {}", &crate_fullname);
+            unsafe_ops += usize::from(match &bb.term {
+                Terminator::Invoke(invoke) => is_unsafe_call(invoke.function.as_ref().right()),
+                Terminator::Unreachable(_) => {
+                    !matches!(bb.instrs.last(), Some(Instruction::Call(_)))
+                        && !implicitly_unreachable.contains(&bb.name)
                 }
-            }
+                _ => false,
+            });
         }
+
+        if unsafe_ops > 0 {
+            result.functions.unsafe_ += 1;
+            result.exprs.unsafe_ += u32::try_from(unsafe_ops).unwrap_or(u32::MAX);
+        } else {
+            result.functions.safe += 1;
+        }
+    }
+
+    result
+}
+
+/// Count unsafe usage of a crate from all modules in its bytecode directory, see
+/// `count_unsafe_ir`.
+///
+/// # Errors
+/// Returns `Error::IoError` if the directory can not be read, or `Error::LLVMError` if a module
+/// fails to parse.
+pub fn count_unsafe_bytecode<P: AsRef<Path>>(crate_bc_dir: P) -> Result<CountUnsafeResult, Error> {
+    let (modules, failures) = load_modules_from_dir(crate_bc_dir);
+    if let Some((_, e)) = failures.into_iter().next() {
+        return Err(e);
+    }
+
+    let mut result = CountUnsafeResult::default();
+    for module in &modules {
+        result.add(&count_unsafe_ir(module));
+    }
+
+    Ok(result)
+}
+
+/// Name of the external tool used to count unsafe usage within crate sources.
+const COUNT_UNSAFE_BIN: &str = "count-unsafe";
+
+/// Maps a failure to execute `COUNT_UNSAFE_BIN` into an error, distinguishing it being missing.
+fn count_unsafe_spawn_error(e: std::io::Error) -> Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        Error::MissingTool(COUNT_UNSAFE_BIN)
+    } else {
+        Error::IoError(e)
     }
+}
+
+/// Verify the `count-unsafe` tool can be executed, so a sweep fails up front rather than on its
+/// first crate.
+///
+/// # Errors
+/// Returns `Error::MissingTool` if the tool is not found on the `PATH`.
+pub(crate) fn check_count_unsafe() -> Result<(), Error> {
+    std::process::Command::new(COUNT_UNSAFE_BIN)
+        .arg("--help")
+        .output()
+        .map_err(count_unsafe_spawn_error)?;
+
     Ok(())
 }
-pub(crate) async fn count_unsafe_crate(c: Crate, roots: Roots, db: Arc<Db>) -> Result<(), Error> {
-    let compressed_root = &roots.compressed_root;
-    let sources_root = &roots.sources_root;
+
+/// Run the `count-unsafe` tool over a crates source directory, returning its parsed results or
+/// `None` if the tool itself failed on this crate.
+///
+/// # Errors
+/// Returns `Error::MissingTool` if the tool could not be found, or `Error::CountUnsafeOutput` if
+/// its output could not be decoded.
+fn run_count_unsafe(path: &Path) -> Result<Option<CountUnsafeResult>, Error> {
+    let output = std::process::Command::new(COUNT_UNSAFE_BIN)
+        .arg(path)
+        .output()
+        .map_err(count_unsafe_spawn_error)?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let raw_json =
+        std::str::from_utf8(&output.stdout).map_err(|e| Error::CountUnsafeOutput(e.to_string()))?;
+    log::trace!("{}", &raw_json);
+
+    serde_json::from_str(raw_json)
+        .map(Some)
+        .map_err(|e| Error::CountUnsafeOutput(e.to_string()))
+}
+
+/// Store the unsafe counts of a crate version. If the version does not exist in the database and
+/// `create_missing` is set, the version and its dependencies are first inserted from the index.
+async fn store_unsafe(
+    db: &dyn GraphStore,
+    v: &crates_index::Version,
+    unsafe_result: &CountUnsafeResult,
+    create_missing: bool,
+) -> Result<(), Error> {
+    match db.set_unsafe(v.name(), v.version(), unsafe_result).await {
+        Err(crate::db::Error::CrateNotFound(_)) if create_missing => {
+            log::debug!("{}-{} missing, inserting", v.name(), v.version());
+            db.insert_crate_version(
+                v.name(),
+                v.version(),
+                v.is_yanked(),
+                &crate::index::version_dependencies(v),
+            )
+            .await?;
+            db.set_unsafe(v.name(), v.version(), unsafe_result).await?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Count unsafe usage of every version of a crate available through the `CrateFs`, storing the
+/// results in the database. Sources are opened through `CrateFs::open`, extracting the `.crate`
+/// file if needed, and closed again once counted so extracted sources are cleaned up. Versions
+/// without a `.crate` file or extracted sources are skipped. The `CrateFs` is only locked to open
+/// and close the sources, not while `count-unsafe` runs. Returns the number of versions which
+/// failed to be opened, counted or stored.
+///
+/// # Panics
+/// This function will panic if the `CrateFs` lock has been poisoned.
+/// # Errors
+/// Returns `Error::MissingTool` if the `count-unsafe` tool is not available.
+pub(crate) async fn count_unsafe_crate(
+    c: Crate,
+    fs: Arc<Mutex<CrateFs>>,
+    db: Arc<dyn GraphStore>,
+    create_missing: bool,
+) -> Result<usize, Error> {
+    let mut failures = 0;
 
     for v in c.versions() {
         let crate_fullname = format!("{}-{}", v.name(), v.version());
-        let crate_path = sources_root.join(format!("{}", &crate_fullname));
-
-        // Lets work off the tgz for now, since we cant extract
-        // TODO: this needs to be unified to a file driver
-        if std::fs::metadata(&crate_path).is_ok() {
-            // Run our count
-            let output = std::process::Command::new("count-unsafe")
-                .args([&crate_path])
-                .output()
-                .unwrap();
-            if output.status.success() {
-                let raw_json = std::str::from_utf8(&output.stdout).unwrap();
-                log::trace!("{}", &raw_json);
-
-                let unsafe_result: CountUnsafeResult = serde_json::from_str(raw_json).unwrap();
-                if unsafe_result.has_unsafe() {
-                    log::debug!("{} unsafe", &crate_fullname);
-                    db.set_unsafe(v.name(), v.version(), &unsafe_result).await;
-                    //.unwrap();
-                }
+
+        // The std mutex is only locked on the blocking pool, and only to open or close the
+        // sources, so neither the runtime nor other crates wait on extraction or the count
+        let opened = {
+            let fs = fs.clone();
+            let fullname = crate_fullname.clone();
+            tokio::task::spawn_blocking(move || {
+                fs.lock()
+                    .unwrap()
+                    .open(&fullname)
+                    .map(|entry| entry.path().to_path_buf())
+            })
+            .await
+        };
+        let path = match opened {
+            Ok(Ok(path)) => path,
+            Ok(Err(crate::crate_fs::Error::CrateFileNotFound)) => continue,
+            Ok(Err(e)) => {
+                log::error!("Failed opening {}: {}", &crate_fullname, e);
+                failures += 1;
+                continue;
+            }
+            Err(e) => {
+                log::error!("Opening {} stopped: {}", &crate_fullname, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        let counted = match tokio::task::spawn_blocking(move || run_count_unsafe(&path)).await {
+            Ok(counted) => counted,
+            Err(e) => {
+                log::error!("Counting unsafe of {} stopped: {}", &crate_fullname, e);
+                failures += 1;
+                Ok(None)
+            }
+        };
+
+        // Closing drops the cache entry once unused, removing any sources extracted for this
+        // count. The sources are pinned until then, so they can not be evicted mid-count.
+        let closing = {
+            let fs = fs.clone();
+            let fullname = crate_fullname.clone();
+            tokio::task::spawn_blocking(move || fs.lock().unwrap().close(&fullname))
+        };
+        let _ = closing.await;
+
+        let unsafe_result = match counted {
+            Ok(Some(unsafe_result)) => unsafe_result,
+            Ok(None) => continue,
+            Err(e @ Error::MissingTool(_)) => return Err(e),
+            Err(e) => {
+                log::error!("Failed counting unsafe of {}: {}", &crate_fullname, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        if unsafe_result.has_unsafe() {
+            log::debug!("{} unsafe", &crate_fullname);
+            if let Err(e) = store_unsafe(db.as_ref(), v, &unsafe_result, create_missing).await {
+                log::error!("Failed storing unsafe counts of {}: {}", &crate_fullname, e);
+                failures += 1;
             }
         }
     }
-    Ok(())
+    Ok(failures)
 }
 
-pub(crate) async fn count_unsafe(roots: &Roots, db: Arc<Db>) -> Result<(), Error> {
+/// Number of crates `count_unsafe` counts concurrently, each holding at most one crate open.
+pub const COUNT_UNSAFE_TASKS: usize = 128;
+
+/// Count unsafe usage across all crates in the index, see `count_unsafe_crate`. Returns the total
+/// number of versions which failed to be counted or stored.
+///
+/// # Errors
+/// Returns `Error::MissingTool` if the `count-unsafe` tool is not available.
+pub(crate) async fn count_unsafe(
+    fs: CrateFs,
+    db: Arc<dyn GraphStore>,
+    create_missing: bool,
+) -> Result<usize, Error> {
+    let mut failures = 0;
+
+    check_count_unsafe()?;
+
     let index = crates_index::Index::new_cargo_default().map_err(crate::index::Error::from)?;
+    let fs = Arc::new(Mutex::new(fs));
 
-    let iter = index.crates().array_chunks::<128>();
+    let iter = index.crates().array_chunks::<COUNT_UNSAFE_TASKS>();
     for chunk in iter {
         let tasks: Vec<_> = chunk
             .into_iter()
-            .map(|c| count_unsafe_crate(c, roots.clone(), db.clone()))
+            .map(|c| count_unsafe_crate(c, fs.clone(), db.clone(), create_missing))
             .collect();
 
-        futures::future::join_all(tasks).await;
+        for result in futures::future::join_all(tasks).await {
+            failures += result?;
+        }
     }
 
-    Ok(())
+    Ok(failures)
+}
+
+/// Count unsafe usage across all crates in the index from their bytecode rather than their
+/// sources, see `count_unsafe_bytecode`. Versions without a bytecode directory in `bc_root` are
+/// skipped. Returns the number of versions which failed to be counted or stored.
+///
+/// # Errors
+/// Returns `painter::Error` if the index can not be opened.
+pub(crate) async fn count_unsafe_from_bytecode(
+    bc_root: &Path,
+    db: Arc<dyn GraphStore>,
+    create_missing: bool,
+) -> Result<usize, Error> {
+    let mut failures = 0;
+
+    let index = crates_index::Index::new_cargo_default().map_err(crate::index::Error::from)?;
+
+    for c in index.crates() {
+        for v in c.versions() {
+            let crate_fullname = format!("{}-{}", v.name(), v.version());
+            let crate_bc_dir = bc_root.join(&crate_fullname);
+            if !crate_bc_dir.exists() {
+                continue;
+            }
+
+            let unsafe_result = match count_unsafe_bytecode(&crate_bc_dir) {
+                Ok(unsafe_result) => unsafe_result,
+                Err(e) => {
+                    log::error!("Failed counting unsafe of {}: {}", &crate_fullname, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+
+            if unsafe_result.has_unsafe() {
+                log::debug!("{} unsafe", &crate_fullname);
+                if let Err(e) = store_unsafe(db.as_ref(), v, &unsafe_result, create_missing).await {
+                    log::error!("Failed storing unsafe counts of {}: {}", &crate_fullname, e);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    Ok(failures)
 }
 
 #[allow(dead_code)]
 fn export_crate_csv<P: AsRef<Path>>(crate_bc_dir: P) -> Result<(), Error> {
-    let calls = extract_calls(&crate_bc_dir)?;
+    let calls = extract_calls(&crate_bc_dir, &[])?;
     let crate_fullname = crate_bc_dir.as_ref().file_name().unwrap().to_str().unwrap();
 
     {
@@ -273,3 +1213,126 @@ fn export_all_csv<P: AsRef<Path>>(bc_root: P) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle_symbol_hash() {
+        let symbol = "_ZN4core3fmt5write17h0123456789abcdefE";
+
+        assert_eq!(demangle_symbol(symbol, false), "core::fmt::write");
+        assert_eq!(
+            demangle_symbol(symbol, true),
+            "core::fmt::write::h0123456789abcdef"
+        );
+        assert_eq!(demangle_symbol("llvm.assume", false), "llvm.assume");
+    }
+
+    #[test]
+    fn classify_function_names() {
+        for (name, class) in [
+            ("llvm.memcpy.p0.p0.i64", FunctionClass::Intrinsic),
+            ("std::io::stdio::_print", FunctionClass::RustStd),
+            ("core::panicking::panic", FunctionClass::RustCore),
+            (
+                "alloc::raw_vec::capacity_overflow",
+                FunctionClass::RustAlloc,
+            ),
+            ("__rust_alloc", FunctionClass::CompilerRt),
+            (
+                "<alloc::string::String as core::fmt::Display>::fmt",
+                FunctionClass::RustAlloc,
+            ),
+            (
+                "<serde::de::value::Error as core::fmt::Debug>::fmt",
+                FunctionClass::User,
+            ),
+            ("serde_json::de::from_str", FunctionClass::User),
+            ("memcpy", FunctionClass::User),
+        ] {
+            assert_eq!(classify_function(name), class, "{name}");
+        }
+    }
+
+    #[test]
+    fn default_call_exclusions() {
+        for (caller, callee) in [
+            ("serde_json::de::from_str", "core::str::from_utf8"),
+            ("<T as core::fmt::Debug>::fmt", "serde_json::ser::to_string"),
+            (
+                "<alloc::vec::Vec<T> as serde::ser::Serialize>::serialize",
+                "serde::ser::Serializer::collect_seq",
+            ),
+            ("simple_test::main", "__rust_dealloc"),
+            ("tokio::runtime::Builder::build", "tokio::rt::spawn"),
+        ] {
+            assert!(
+                is_excluded_call(caller, callee, &[]),
+                "{caller} -> {callee}"
+            );
+        }
+        assert!(!is_excluded_call(
+            "<serde::de::value::Error as serde::de::Error>::custom",
+            "serde::de::value::Error::new",
+            &[]
+        ));
+
+        // Given patterns replace the defaults
+        let exclude = ["serde_json::".to_string()];
+        assert!(is_excluded_call(
+            "serde_json::de::from_str",
+            "foo::bar",
+            &exclude
+        ));
+        assert!(!is_excluded_call(
+            "foo::bar",
+            "core::str::from_utf8",
+            &exclude
+        ));
+
+        // Classes match by classification rather than anywhere in the name
+        let exclude = ["class:compiler-rt".to_string(), "class:core".to_string()];
+        assert!(is_excluded_call("foo::bar", "__rdl_alloc", &exclude));
+        assert!(is_excluded_call(
+            "foo::bar",
+            "core::str::from_utf8",
+            &exclude
+        ));
+        assert!(!is_excluded_call(
+            "<T as core::fmt::Debug>::fmt",
+            "foo::bar",
+            &exclude
+        ));
+        assert!(!is_excluded_call(
+            "foo::bar",
+            "core::str::from_utf8",
+            &["class:unknown".to_string()]
+        ));
+    }
+
+    #[test]
+    fn count_unsafe_safe_crate() {
+        // count_unsafe_bytecode loads every module in a directory, so copy out a single crate
+        // Unique per process so concurrent test runs don't share the directory
+        let dir = std::env::temp_dir().join(format!(
+            "painter-count-unsafe-simple_test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(
+            "test_data/simple_test-128fe9b9c0bcc910.bc",
+            dir.join("simple_test-128fe9b9c0bcc910.bc"),
+        )
+        .unwrap();
+
+        let result = count_unsafe_bytecode(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let result = result.unwrap();
+
+        assert!(result.functions.safe > 0);
+        assert_eq!(result.functions.unsafe_, 0);
+        assert_eq!(result.exprs.unsafe_, 0);
+    }
+}
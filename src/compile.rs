@@ -1,10 +1,17 @@
 #![allow(clippy::module_name_repetitions)]
 
-use crate::crate_fs::{CrateCache, CrateEntry, CrateFs};
+use crate::{
+    crate_fs::{CrateCache, CrateEntry, CrateFs},
+    index::CrateFilter,
+};
 use crates_index::{Crate, Index};
 use std::{
+    collections::BTreeMap,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 use walkdir::WalkDir;
 
@@ -28,6 +35,43 @@ pub enum Error {
     ///
     #[error("Indexing Error: {0}")]
     CrateFsError(#[from] crate::crate_fs::Error),
+    ///
+    #[error("Failures file invalid: {0}")]
+    FailuresError(#[from] serde_json::Error),
+}
+
+/// Toolchain crates are compiled with when none is configured, as passed to `cargo +<toolchain>`.
+pub const DEFAULT_TOOLCHAIN: &str = "1.67";
+
+/// How crates are compiled to bytecode.
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CodegenMode {
+    /// Emit the bytecode of the crate alone with LTO disabled, so calls into dependencies are kept
+    /// as calls. Generic instantiations of dependency code may be missing from the bytecode.
+    #[default]
+    NoLto,
+    /// Build the crate as a `cdylib` with thin LTO, linking in the bytecode of its dependencies so
+    /// cross-crate monomorphizations appear in the emitted bytecode. This produces much larger
+    /// bytecode and slower compiles, and fails for crates which can not be built as a `cdylib`,
+    /// such as proc macros.
+    ThinLtoLink,
+}
+impl CodegenMode {
+    /// Arguments passed to `cargo rustc`.
+    fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            CodegenMode::NoLto => &[],
+            CodegenMode::ThinLtoLink => &["--crate-type", "cdylib"],
+        }
+    }
+
+    /// Codegen arguments passed through to `rustc`.
+    fn rustc_args(self) -> &'static [&'static str] {
+        match self {
+            CodegenMode::NoLto => &["-C", "lto=off"],
+            CodegenMode::ThinLtoLink => &["-C", "lto=thin"],
+        }
+    }
 }
 
 /// Executes a cargo clean within the crates sources directory. This is executed within the
@@ -39,10 +83,10 @@ pub enum Error {
 /// # Errors
 /// returns an instance of `Error::CleanFailure`, containing the output of stdout and stderr from the
 /// execution.
-pub fn clean(path: &Path) -> Result<(), Error> {
+pub fn clean(path: &Path, toolchain: &str) -> Result<(), Error> {
     // cargo rustc --release -- -g --emit=llvm-bc
     let output = std::process::Command::new("cargo")
-        .arg("+1.60")
+        .arg(format!("+{toolchain}"))
         .arg("clean")
         .current_dir(path)
         .output()
@@ -69,37 +113,35 @@ pub fn clean(path: &Path) -> Result<(), Error> {
 /// # Errors
 /// returns an instance of `Error::CompileFailed`, containing the output of stdout and stderr from the
 /// execution.
-fn compile_crate<P: AsRef<Path>>(
+pub fn compile_crate<P: AsRef<Path>>(
     name: &str,
     version: &str,
     src_path: P,
     bc_root: P,
+    toolchain: &str,
+    codegen_mode: CodegenMode,
 ) -> Result<(), Error> {
     let fullname = format!("{}-{}", &name, version);
     let output_dir = bc_root.as_ref().join(&fullname);
 
+    let span = crate::logging::Span::enter("compile", name, version);
     log::info!("Compiling: {} @ {}", &fullname, output_dir.display());
 
-    // Build the crate with rustc, emitting llvm-bc. We also disable LTO to prevent some inlining
-    // to gain better cross-crate function call introspection.
+    // Build the crate with rustc, emitting llvm-bc. By default we also disable LTO to prevent some
+    // inlining to gain better cross-crate function call introspection, see `CodegenMode`.
     // TODO: We should further limit optimizations and inlining to get an even better picture.
     let output = std::process::Command::new("cargo")
-        .args([
-            "+1.67",
-            "rustc",
-            "--release",
-            "--lib",
-            "--",
-            "-g",
-            "--emit=llvm-bc",
-            "-C",
-            "lto=off",
-        ])
+        .arg(format!("+{toolchain}"))
+        .args(["rustc", "--release", "--lib"])
+        .args(codegen_mode.cargo_args())
+        .args(["--", "-g", "--emit=llvm-bc"])
+        .args(codegen_mode.rustc_args())
         .current_dir(src_path.as_ref())
         .output()
         .unwrap();
 
     log::trace!("Compiled: {} with result: {:?}", fullname, output);
+    span.finish();
 
     if output.status.success() {
         std::fs::create_dir(&output_dir);
@@ -118,9 +160,9 @@ fn compile_crate<P: AsRef<Path>>(
                 std::fs::copy(e.path(), &dst).unwrap();
             });
 
-        clean(src_path.as_ref())?;
+        clean(src_path.as_ref(), toolchain)?;
     } else {
-        clean(src_path.as_ref())?;
+        clean(src_path.as_ref(), toolchain)?;
 
         return Err(Error::CompileFailed(format!(
             "{}\n-----------\n{}",
@@ -132,17 +174,254 @@ fn compile_crate<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Counts of the crates handled by `compile_all`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompileSummary {
+    /// Crates which were compiled successfully.
+    pub built: usize,
+    /// Crates which were skipped because their bytecode already exists.
+    pub skipped: usize,
+    /// Crates which failed to open or compile.
+    pub failed: usize,
+    /// Crates which were skipped because they failed to compile in a previous run.
+    pub known_failures: usize,
+}
+
+/// Counts of the crates a `compile_all` run would handle, see `plan_compile_all`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompilePlan {
+    /// Crates in the index.
+    pub total: usize,
+    /// Crates which already have bytecode.
+    pub built: usize,
+    /// Crates which would be skipped because they failed to compile in a previous run.
+    pub known_failures: usize,
+    /// Crates which would be compiled.
+    pub to_compile: usize,
+    /// Total size of the existing bytecode.
+    pub bytecode_bytes: u64,
+}
+impl CompilePlan {
+    /// Estimate the size of the bytecode of the crates to compile, from the average size of the
+    /// existing bytecode. Returns `None` if no crates have been built yet.
+    #[must_use]
+    pub fn estimated_bytes(&self) -> Option<u64> {
+        let built = u64::try_from(self.built).ok().filter(|&b| b > 0)?;
+        let to_compile = u64::try_from(self.to_compile).ok()?;
+
+        Some(self.bytecode_bytes / built * to_compile)
+    }
+}
+
+/// Name of the file within the bytecode root recording crates which failed to compile.
+pub const FAILURES_FILE: &str = "failures.jsonl";
+
+/// Maximum length of the compiler output kept as the reason of a failure.
+const FAILURE_REASON_LEN: usize = 4096;
+
+/// A single line of the `FAILURES_FILE`. A crate which has since compiled is recorded without a
+/// reason, superseding its earlier failure.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct FailureRecord {
+    #[serde(rename = "crate")]
+    fullname: String,
+    reason: Option<String>,
+}
+
+/// Crates which failed to compile, keyed by `name-version` with the reason of the failure. These
+/// are persisted to `FAILURES_FILE` so permanently broken crates are not attempted on every run.
+///
+/// Changes are appended to the file as JSON Lines, so recording a failure does not rewrite every
+/// failure before it. `CompileFailures::compact` drops superseded lines once a run completes.
+#[derive(Debug, Default)]
+pub struct CompileFailures {
+    path: PathBuf,
+    failures: BTreeMap<String, String>,
+    file: Option<std::fs::File>,
+}
+impl CompileFailures {
+    /// Load the failures recorded at the given path, or start empty if the file does not exist.
+    /// Later lines of the file supersede earlier lines for the same crate, and lines which can
+    /// not be parsed are logged and skipped.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::compile::Error` if the file exists but can not be read.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        use std::io::BufRead;
+
+        let path = path.as_ref().to_path_buf();
+        let mut failures = BTreeMap::new();
+        if path.exists() {
+            for line in std::io::BufReader::new(std::fs::File::open(&path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                // An interrupted run may leave a truncated last line, which is dropped
+                let record: FailureRecord = match serde_json::from_str(&line) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        log::warn!("Skipping invalid line of {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                match record.reason {
+                    Some(reason) => failures.insert(record.fullname, reason),
+                    None => failures.remove(&record.fullname),
+                };
+            }
+        }
+
+        Ok(Self {
+            path,
+            failures,
+            file: None,
+        })
+    }
+
+    /// Returns true if the crate failed in a previous run.
+    #[must_use]
+    pub fn contains(&self, fullname: &str) -> bool {
+        self.failures.contains_key(fullname)
+    }
+
+    /// Record the failure of a crate, appending it to the file.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::compile::Error` if the file can not be written.
+    pub fn insert(&mut self, fullname: &str, error: &Error) -> Result<(), Error> {
+        let reason = failure_reason(error);
+        self.failures.insert(fullname.to_string(), reason.clone());
+        self.append(&FailureRecord {
+            fullname: fullname.to_string(),
+            reason: Some(reason),
+        })
+    }
+
+    /// Remove a crate which has since compiled successfully, appending the change to the file.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::compile::Error` if the file can not be written.
+    pub fn remove(&mut self, fullname: &str) -> Result<(), Error> {
+        if self.failures.remove(fullname).is_some() {
+            self.append(&FailureRecord {
+                fullname: fullname.to_string(),
+                reason: None,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the file with a single line per current failure, dropping superseded lines.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::compile::Error` if the file can not be written.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.file = None;
+
+        let mut contents = Vec::new();
+        for (fullname, reason) in &self.failures {
+            serde_json::to_writer(
+                &mut contents,
+                &FailureRecord {
+                    fullname: fullname.clone(),
+                    reason: Some(reason.clone()),
+                },
+            )?;
+            contents.push(b'\n');
+        }
+        std::fs::write(&self.path, contents)?;
+
+        Ok(())
+    }
+
+    /// Append a single record to the file, opening it on first use.
+    fn append(&mut self, record: &FailureRecord) -> Result<(), Error> {
+        use std::io::Write;
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+
+        // Written with a single call, so an interrupted run leaves at most a truncated last line
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the reason stored for a failed compile. For compiler failures this is the tail of the
+/// captured output, where the errors from `stderr` are found.
+fn failure_reason(error: &Error) -> String {
+    match error {
+        Error::CompileFailed(output) => {
+            let start = output
+                .char_indices()
+                .rev()
+                .nth(FAILURE_REASON_LEN - 1)
+                .map_or(0, |(i, _)| i);
+            output[start..].to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Returns true if the bytecode directory of a crate exists and contains any `.bc` or `.ll` files.
+fn has_bytecode(dir: &Path) -> bool {
+    std::fs::read_dir(dir).map_or(false, |entries| {
+        entries
+            .filter_map(Result::ok)
+            .any(|e| crate::analysis::is_module_path(&e.path()))
+    })
+}
+
 /// Walks the entire `Roots::sources_root` and attempts to compile all crates in parallel.
+///
+/// Crates which already have modules in their bytecode directory are skipped, so re-running after
+/// new crates are added only compiles the new ones, unless `rebuild` is set, in which case every
+/// crate is compiled again.
+///
+/// Crates which fail to compile are recorded in `FAILURES_FILE` within `bc_root` and skipped on
+/// later runs, unless `retry_failures` is set. Crates are built with `cargo +<toolchain>`, see
+/// `DEFAULT_TOOLCHAIN`, and emit bytecode as configured by `codegen_mode`. Only crates matching
+/// `filter` are considered.
+///
+/// # Panics
+/// This function will panic if the `CrateFs` lock has been poisoned.
+/// # Errors
+/// Returns an instance of `painter::compile::Error` if the index or failures file can not be
+/// opened, or the failures file can not be compacted once all crates are done. Failures of
+/// individual crates are logged and counted in the returned `CompileSummary`.
 pub async fn compile_all<P: AsRef<Path> + Send + Sync>(
-    mut fs: CrateFs,
+    fs: CrateFs,
     bc_root: P,
-) -> Result<(), Error> {
+    rebuild: bool,
+    retry_failures: bool,
+    toolchain: &str,
+    codegen_mode: CodegenMode,
+    filter: &CrateFilter,
+) -> Result<CompileSummary, Error> {
     use rayon::iter::ParallelIterator;
 
     // iterate the dir of crates and iterate them via the FS cache
     let index = Index::new_cargo_default()?;
 
     let fs = Arc::new(Mutex::new(fs));
+    let failures = Mutex::new(CompileFailures::load(bc_root.as_ref().join(FAILURES_FILE))?);
+    let built = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let known_failures = AtomicUsize::new(0);
 
     let do_crate = |c: Crate, fs: Arc<Mutex<CrateFs>>, bc_root: PathBuf| {
         log::trace!("enter: {}", c.name());
@@ -151,10 +430,18 @@ pub async fn compile_all<P: AsRef<Path> + Send + Sync>(
         let v = c.latest_version();
 
         let fullname = format!("{}-{}", c.name(), v.version());
+        let _span = crate::logging::Span::enter("compile", c.name(), v.version());
         log::trace!("Opening: {}", fullname);
 
-        if (bc_root.join(&fullname).exists()) {
-            log::info!("{} bytecode exists, skipping..", &fullname);
+        if !rebuild && has_bytecode(&bc_root.join(&fullname)) {
+            log::debug!("{} bytecode exists, skipping..", &fullname);
+            skipped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        if !retry_failures && failures.lock().unwrap().contains(&fullname) {
+            log::debug!("{} failed previously, skipping..", &fullname);
+            known_failures.fetch_add(1, Ordering::Relaxed);
             return;
         }
 
@@ -164,12 +451,34 @@ pub async fn compile_all<P: AsRef<Path> + Send + Sync>(
                 entry.path().to_path_buf()
             } else {
                 log::error!("Opening failed on {}", fullname);
+                failed.fetch_add(1, Ordering::Relaxed);
                 return;
             }
         };
 
-        if let Err(e) = compile_crate(c.name(), v.version(), &cache, &bc_root) {
-            log::error!("{:?}", e);
+        let result = compile_crate(
+            c.name(),
+            v.version(),
+            &cache,
+            &bc_root,
+            toolchain,
+            codegen_mode,
+        );
+        // Let the sources be evicted again now that the compile is done with them
+        let _ = fs.lock().unwrap().release(&fullname);
+        let recorded = match &result {
+            Err(e) => {
+                log::error!("{:?}", e);
+                failed.fetch_add(1, Ordering::Relaxed);
+                failures.lock().unwrap().insert(&fullname, e)
+            }
+            Ok(()) => {
+                built.fetch_add(1, Ordering::Relaxed);
+                failures.lock().unwrap().remove(&fullname)
+            }
+        };
+        if let Err(e) = recorded {
+            log::error!("Failed recording result of {}: {}", fullname, e);
         }
         //}
     };
@@ -177,9 +486,101 @@ pub async fn compile_all<P: AsRef<Path> + Send + Sync>(
     index
         .crates_parallel()
         .filter_map(|c| c.ok())
+        .filter(|c| filter.matches(c.name()))
         .for_each(|c| {
             do_crate(c, fs.clone(), bc_root.as_ref().to_path_buf());
         });
 
-    Ok(())
+    failures.into_inner().unwrap().compact()?;
+
+    Ok(CompileSummary {
+        built: built.into_inner(),
+        skipped: skipped.into_inner(),
+        failed: failed.into_inner(),
+        known_failures: known_failures.into_inner(),
+    })
+}
+
+/// Count the crates `compile_all` would compile with the same `rebuild`, `retry_failures`
+/// and `filter`, without extracting or compiling anything.
+///
+/// # Errors
+/// Returns an instance of `painter::compile::Error` if the index or failures file can not be
+/// opened.
+pub fn plan_compile_all<P: AsRef<Path>>(
+    bc_root: P,
+    rebuild: bool,
+    retry_failures: bool,
+    filter: &CrateFilter,
+) -> Result<CompilePlan, Error> {
+    let index = Index::new_cargo_default()?;
+    let failures = CompileFailures::load(bc_root.as_ref().join(FAILURES_FILE))?;
+    let mut plan = CompilePlan::default();
+
+    for c in index.crates().filter(|c| filter.matches(c.name())) {
+        // Matches compile_all, which currently only builds the latest version
+        let fullname = format!("{}-{}", c.name(), c.latest_version().version());
+        let bc_dir = bc_root.as_ref().join(&fullname);
+
+        plan.total += 1;
+        if has_bytecode(&bc_dir) {
+            plan.built += 1;
+            plan.bytecode_bytes += crate::analysis::module_bytes(&bc_dir);
+            if !rebuild {
+                continue;
+            }
+        }
+
+        if !retry_failures && failures.contains(&fullname) {
+            plan.known_failures += 1;
+            continue;
+        }
+
+        plan.to_compile += 1;
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_failures_append_and_compact() {
+        // Unique per process so concurrent test runs don't share the file
+        let path = std::env::temp_dir().join(format!(
+            "painter-compile-failures-test-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut failures = CompileFailures::load(&path).unwrap();
+        failures
+            .insert(
+                "foo-1.0.0",
+                &Error::CompileFailed("error[E0425]".to_string()),
+            )
+            .unwrap();
+        failures
+            .insert(
+                "bar-0.1.0",
+                &Error::CompileFailed("error[E0308]".to_string()),
+            )
+            .unwrap();
+        failures.remove("foo-1.0.0").unwrap();
+        drop(failures);
+
+        // Every change is a line, and the last line of a crate wins
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        let mut failures = CompileFailures::load(&path).unwrap();
+        assert!(!failures.contains("foo-1.0.0"));
+        assert!(failures.contains("bar-0.1.0"));
+
+        failures.compact().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(CompileFailures::load(&path).unwrap().contains("bar-0.1.0"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,137 @@
+use crate::Backend;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    ///
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+    ///
+    #[error("Invalid config file: {0}")]
+    TomlError(#[from] toml::de::Error),
+}
+
+/// Settings read from a `painter.toml` config file, passed with `--config`. Every setting is
+/// optional, and flags given on the command line take precedence over the file.
+///
+/// ```toml
+/// toolchain = "1.67"
+/// exclude-patterns = ["llvm.", "std::"]
+/// cache-capacity = 256
+///
+/// [export]
+/// queue-depth = 32
+/// writers = 4
+///
+/// [db]
+/// host = "bolt://127.0.0.1:7687"
+/// username = "neo4j"
+/// password = "changeme123"
+///
+/// [roots]
+/// sources = "cargo_sources"
+/// bytecodes = "cargo_bytecodes"
+/// compressed = "cargo_crates"
+/// ```
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Database backend and connection parameters.
+    pub db: DbSection,
+    /// Root directories of the work session.
+    pub roots: RootsConfig,
+    /// Rust toolchain crates are compiled with, as passed to `cargo +<toolchain>`.
+    pub toolchain: Option<String>,
+    /// Exclusion patterns used when none are given with `--exclude-pattern`.
+    pub exclude_patterns: Vec<String>,
+    /// Maximum number of extracted crates kept by the `CrateFs` cache.
+    pub cache_capacity: Option<usize>,
+    /// Sizing of the database export queue.
+    pub export: ExportConfig,
+}
+impl Config {
+    /// Read and parse a config file.
+    ///
+    /// # Errors
+    /// Returns `painter::config::Error` if the file can not be read or is not a valid config.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the contents of a config file.
+    ///
+    /// # Errors
+    /// Returns `Error::TomlError` if the contents are not a valid config.
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// The `[db]` section of the config file, see `DbArgs`. Named apart from `db::DbConfig`, which
+/// holds the resolved connection parameters.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DbSection {
+    pub backend: Option<Backend>,
+    pub host: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+/// The `[roots]` section of the config file, see `Roots`.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RootsConfig {
+    pub sources: Option<PathBuf>,
+    pub bytecodes: Option<PathBuf>,
+    pub compressed: Option<PathBuf>,
+}
+
+/// The `[export]` section of the config file, see `analysis::ExportQueueConfig`.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExportConfig {
+    pub queue_depth: Option<usize>,
+    pub writers: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            toolchain = "1.67"
+            exclude-patterns = ["llvm."]
+
+            [db]
+            host = "bolt://127.0.0.1:7687"
+            username = "neo4j"
+
+            [roots]
+            bytecodes = "cargo_bytecodes"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.toolchain.as_deref(), Some("1.67"));
+        assert_eq!(config.exclude_patterns, ["llvm."]);
+        assert_eq!(config.cache_capacity, None);
+        assert_eq!(config.db.host.as_deref(), Some("bolt://127.0.0.1:7687"));
+        assert_eq!(config.db.password, None);
+        assert_eq!(
+            config.roots.bytecodes,
+            Some(PathBuf::from("cargo_bytecodes"))
+        );
+        assert_eq!(config.roots.sources, None);
+
+        assert!(matches!(
+            Config::parse("unknown = 1"),
+            Err(Error::TomlError(_))
+        ));
+    }
+}
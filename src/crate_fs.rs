@@ -1,7 +1,9 @@
 #![allow(clippy::module_name_repetitions)]
 use circular_buffer::CircularBuffer;
 use std::{
-    path::{Path, PathBuf},
+    collections::HashMap,
+    io::Read,
+    path::{Component, Path, PathBuf},
     sync::Mutex,
 };
 
@@ -16,8 +18,10 @@ pub enum Error {
     CrateNotFound,
     #[error("CrateFileNotFound")]
     CrateFileNotFound,
-    #[error("CrateFileNotFound")]
+    #[error("ExtractionFailed")]
     ExtractionFailed,
+    #[error("CacheFull")]
+    CacheFull,
     #[error(
         "Crate name contained invalid characters or did not match the NAME-VER format. Name: {0}"
     )]
@@ -27,41 +31,157 @@ pub enum Error {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CrateEntry {
     pub full_name: String,
+    /// Byte offset of the `-` separating the name from the version in `full_name`.
+    split: usize,
 }
 impl CrateEntry {
+    /// Parse a `NAME-VER` crate name. Both names and versions may contain dashes, such as
+    /// `foo-bar-1.0.0-alpha.1`, so the name ends at the first dash which is followed by a valid
+    /// semver version.
+    ///
+    /// # Errors
+    /// Returns `Error::CrateNameError` if no part of the name is followed by a valid version.
     pub fn new(full_name: String) -> Result<Self, Error> {
-        let (_, _) = full_name
-            .rsplit_once('-')
-            .ok_or(Error::CrateNameError(full_name.clone()))?;
-
-        // TODO: Semver check valid here
+        let split = full_name
+            .match_indices('-')
+            .map(|(i, _)| i)
+            .find(|&i| i > 0 && semver::Version::parse(&full_name[i + 1..]).is_ok())
+            .ok_or_else(|| Error::CrateNameError(full_name.clone()))?;
 
-        Ok(Self { full_name })
+        Ok(Self { full_name, split })
     }
     pub fn full_name(&self) -> &str {
         &self.full_name
     }
 
     pub fn name(&self) -> &str {
-        self.full_name.rsplit_once('-').unwrap().0
+        &self.full_name[..self.split]
     }
 
     pub fn version(&self) -> &str {
-        self.full_name.rsplit_once('-').unwrap().1
+        &self.full_name[self.split + 1..]
     }
 
     pub fn filename(&self) -> String {
         format!("{}.crate", self.full_name())
     }
 }
-impl<S> From<S> for CrateEntry
-where
-    S: AsRef<str>,
-{
-    fn from(rhv: S) -> CrateEntry {
-        Self::new(rhv.as_ref().to_string()).unwrap()
+impl TryFrom<String> for CrateEntry {
+    type Error = Error;
+
+    fn try_from(rhv: String) -> Result<Self, Self::Error> {
+        Self::new(rhv)
     }
 }
+impl TryFrom<&str> for CrateEntry {
+    type Error = Error;
+
+    fn try_from(rhv: &str) -> Result<Self, Self::Error> {
+        Self::new(rhv.to_string())
+    }
+}
+
+/// Container object for storing the information of a given crate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CrateSource {
+    /// The crates name
+    pub name: String,
+    /// The crates semver version in `String` format.
+    pub version: String,
+    /// The fully qualified path the crate source was discovered and is located.
+    pub path: PathBuf,
+}
+
+/// Discover all extracted crate sources within a directory, keyed by their `NAME-VER` full name.
+/// Names are split as in `CrateEntry::new`; directories which are not in the `NAME-VER` format
+/// are logged and skipped.
+///
+/// # Errors
+/// Returns `Error::IoError` if the directory can not be read.
+pub fn get_crate_sources<P: AsRef<Path>>(
+    sources_dir: P,
+) -> Result<HashMap<String, CrateSource>, Error> {
+    let mut sources = HashMap::new();
+
+    for e in std::fs::read_dir(sources_dir.as_ref())?.filter_map(Result::ok) {
+        let path = e.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        let entry = match CrateEntry::try_from(e.file_name().to_string_lossy().as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("Skipping {}: {}", path.display(), err);
+                continue;
+            }
+        };
+
+        let source = CrateSource {
+            name: entry.name().to_string(),
+            version: entry.version().to_string(),
+            path,
+        };
+        sources.insert(entry.full_name, source);
+    }
+
+    Ok(sources)
+}
+
+/// Returns true if a path is relative and only descends from the directory it is relative to, so
+/// it has no root, prefix or `..` components.
+fn is_descending_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Returns true if an archive entry path is within the `root` directory of the crate, as in
+/// `serde-1.0.152/src/lib.rs`, and can not escape it.
+fn is_safe_entry_path(path: &Path, root: &str) -> bool {
+    is_descending_path(path)
+        && path
+            .components()
+            .find(|c| *c != Component::CurDir)
+            .map_or(false, |first| first.as_os_str() == root)
+}
+
+/// Unpack every entry of an archive into `dst`. Every entry must be within the `root` directory
+/// of the crate, so a malicious archive can not write into the sources of another crate extracted
+/// next to it. Hard links must target a path within `root`, and symbolic links may only point
+/// below their own directory. Rejected entries fail the unpack before anything is written for
+/// them.
+///
+/// # Errors
+/// Returns `Error::ExtractionFailed` if any entry is rejected, or `Error::IoError` if reading or
+/// writing an entry fails. Entries unpacked before the failure are left in place.
+fn unpack_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    dst: &Path,
+    root: &str,
+) -> Result<(), Error> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        let path = entry.path()?.into_owned();
+        let link_safe = match (entry.header().entry_type(), entry.link_name()?) {
+            (tar::EntryType::Link, Some(link)) => is_safe_entry_path(&link, root),
+            // Symlink targets are relative to the link, and other links of the archive may make
+            // `..` resolve outside of `root`, so only links further down are allowed
+            (tar::EntryType::Symlink, Some(link)) => is_descending_path(&link),
+            _ => true,
+        };
+        if !is_safe_entry_path(&path, root) || !link_safe {
+            log::error!("Rejecting archive entry {}", path.display());
+            return Err(Error::ExtractionFailed);
+        }
+
+        if !entry.unpack_in(dst)? {
+            return Err(Error::ExtractionFailed);
+        }
+    }
+
+    Ok(())
+}
 
 #[derive(Debug)]
 pub struct CrateCache {
@@ -94,7 +214,13 @@ impl CrateCache {
         let tar_gz = std::fs::File::open(&src_crate_file)?;
         let tar = flate2::read::GzDecoder::new(tar_gz);
         let mut archive = tar::Archive::new(tar);
-        archive.unpack(sources_dir.as_ref())?;
+        if let Err(e) = unpack_archive(&mut archive, sources_dir.as_ref(), entry.full_name()) {
+            // Don't leave a partial extraction behind to be mistaken for complete sources
+            if extracted_path.exists() {
+                std::fs::remove_dir_all(&extracted_path)?;
+            }
+            return Err(e);
+        }
 
         if !extracted_path.exists() {
             return Err(Error::ExtractionFailed);
@@ -120,9 +246,19 @@ impl Drop for CrateCache {
     }
 }
 
+/// Maximum number of extracted crates a `CrateFs` keeps open. Opening a crate beyond its capacity
+/// closes the least recently opened crate which is not in use.
+pub const MAX_CACHE_CAPACITY: usize = 1024;
+
+/// Minimum number of extracted crates a `CrateFs` keeps open, so every crate counted at once by
+/// `count-unsafe` fits without growing the cache past its capacity.
+pub const MIN_CACHE_CAPACITY: usize = crate::analysis::COUNT_UNSAFE_TASKS;
+
 pub struct CrateFsConfig {
     pub crates_path: PathBuf,
     pub extract_path: PathBuf,
+    /// Number of extracted crates kept open, within `MIN_CACHE_CAPACITY..=MAX_CACHE_CAPACITY`.
+    pub capacity: usize,
 }
 impl CrateFsConfig {
     pub fn with_paths<P1, P2>(crates_path: P1, extract_path: P2) -> Self
@@ -140,12 +276,29 @@ impl CrateFsConfig {
         Self {
             crates_path,
             extract_path,
+            capacity: MAX_CACHE_CAPACITY,
         }
     }
+
+    /// Limit the number of extracted crates kept open, clamped to
+    /// `MIN_CACHE_CAPACITY..=MAX_CACHE_CAPACITY`.
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.clamp(MIN_CACHE_CAPACITY, MAX_CACHE_CAPACITY);
+        self
+    }
+}
+
+/// An extracted crate kept open by a `CrateFs`, with the number of `open` calls which have not
+/// been released yet. Entries in use are never evicted.
+struct CachedCrate {
+    entry: CrateEntry,
+    cache: CrateCache,
+    pins: usize,
 }
 
 pub struct CrateFs {
-    cache: Box<CircularBuffer<1024, (CrateEntry, CrateCache)>>,
+    cache: Box<CircularBuffer<MAX_CACHE_CAPACITY, CachedCrate>>,
     index: crates_index::Index,
     config: CrateFsConfig,
 }
@@ -161,47 +314,93 @@ impl CrateFs {
     }
 
     fn find_cache_index(&self, entry: &CrateEntry) -> Option<usize> {
-        self.cache.iter().enumerate().find_map(
-            |(i, (e, _))| {
-                if *e == *entry {
-                    Some(i)
-                } else {
-                    None
-                }
-            },
-        )
+        self.cache.iter().enumerate().find_map(|(i, cached)| {
+            if cached.entry == *entry {
+                Some(i)
+            } else {
+                None
+            }
+        })
     }
+
+    /// Release a crate returned by `open` once it is no longer used, keeping its sources
+    /// extracted until it is evicted.
+    ///
+    /// # Errors
+    /// Returns `Error::CrateNotFound` if the crate is not open.
+    pub fn release<S: AsRef<str>>(&mut self, fullname: S) -> Result<(), Error> {
+        let entry = CrateEntry::try_from(fullname.as_ref())?;
+        let index = self.find_cache_index(&entry).ok_or(Error::CrateNotFound)?;
+
+        let cached = self.cache.get_mut(index).ok_or(Error::CrateNotFound)?;
+        cached.pins = cached.pins.saturating_sub(1);
+
+        Ok(())
+    }
+
+    /// Release a crate returned by `open`, and remove its extracted sources unless it is still in
+    /// use by another `open`.
+    ///
+    /// # Errors
+    /// Returns `Error::CrateNotFound` if the crate is not open.
     pub fn close<S: AsRef<str>>(&mut self, fullname: S) -> Result<(), Error> {
-        let entry = CrateEntry::new(fullname.as_ref().to_string())?;
+        self.release(fullname.as_ref())?;
 
-        self.cache
-            .remove(self.find_cache_index(&entry).ok_or(Error::CrateNotFound)?);
+        let entry = CrateEntry::try_from(fullname.as_ref())?;
+        let index = self.find_cache_index(&entry).ok_or(Error::CrateNotFound)?;
+        if self
+            .cache
+            .get(index)
+            .map_or(false, |cached| cached.pins == 0)
+        {
+            self.cache.remove(index);
+        }
 
         Ok(())
     }
 
+    /// Open the sources of a crate, extracting its `.crate` file if needed. The crate is in use,
+    /// and so not evicted, until it is given back with `release` or `close`. If every open crate
+    /// is in use, the cache grows beyond its capacity rather than removing sources in use.
+    ///
+    /// # Errors
+    /// Returns `Error::CrateFileNotFound` if there is neither a `.crate` file nor extracted
+    /// sources, `Error::CacheFull` if `MAX_CACHE_CAPACITY` crates are in use, or the error of
+    /// extracting the crate.
     pub fn open<S: AsRef<str>>(&mut self, fullname: S) -> Result<&CrateCache, Error> {
-        let entry = CrateEntry::new(fullname.as_ref().to_string())?;
+        let entry = CrateEntry::try_from(fullname.as_ref())?;
 
         if let Some(index) = self.find_cache_index(&entry) {
-            Ok(&self.cache.get(index).ok_or(Error::CrateNotFound)?.1)
+            let cached = self.cache.get_mut(index).ok_or(Error::CrateNotFound)?;
+            cached.pins += 1;
+            Ok(&cached.cache)
         } else {
-            // Check that we have the crate file
-            let cratefile_path = self
-                .config
-                .crates_path
-                .join(format!("{}.crate", entry.full_name()));
-            if !cratefile_path.exists() {
+            // Check that we have the crate file, unless its sources were already extracted
+            let cratefile_path = self.config.crates_path.join(entry.filename());
+            let extracted_path = self.config.extract_path.join(entry.full_name());
+            if !cratefile_path.exists() && !extracted_path.exists() {
                 return Err(Error::CrateFileNotFound);
             }
 
-            // Check we have capcity, otherwise purge the front entry
-
-            let cache_entry =
+            // Check we have capcity, otherwise purge the oldest entry which is not in use
+            while self.cache.len() >= self.config.capacity {
+                let Some(index) = self.cache.iter().position(|cached| cached.pins == 0) else {
+                    break;
+                };
+                self.cache.remove(index);
+            }
+            if self.cache.is_full() {
+                return Err(Error::CacheFull);
+            }
+            let cache =
                 CrateCache::new(&entry, &self.config.crates_path, &self.config.extract_path)?;
 
-            self.cache.push_back((entry, cache_entry));
-            Ok(&self.cache.back().ok_or(Error::CrateNotFound)?.1)
+            self.cache.push_back(CachedCrate {
+                entry,
+                cache,
+                pins: 1,
+            });
+            Ok(&self.cache.back().ok_or(Error::CrateNotFound)?.cache)
         }
     }
 
@@ -218,4 +417,58 @@ mod tests {
         // capture log messages with test harness
         let _ = env_logger::builder().is_test(true).try_init();
     }
+
+    #[test]
+    fn crate_entry_names() {
+        init_logging();
+
+        for (full_name, name, version) in [
+            ("serde-1.0.152", "serde", "1.0.152"),
+            ("foo-bar-1.2.3", "foo-bar", "1.2.3"),
+            ("tokio-1.0.0-alpha.1", "tokio", "1.0.0-alpha.1"),
+            ("foo-2d-0.1.0+build-5", "foo-2d", "0.1.0+build-5"),
+        ] {
+            let entry = CrateEntry::new(full_name.to_string()).unwrap();
+            assert_eq!(entry.name(), name);
+            assert_eq!(entry.version(), version);
+        }
+    }
+
+    #[test]
+    fn archive_entry_paths() {
+        let root = "serde-1.0.152";
+        for path in [
+            "serde-1.0.152",
+            "serde-1.0.152/src/lib.rs",
+            "./serde-1.0.152/Cargo.toml",
+        ] {
+            assert!(is_safe_entry_path(Path::new(path), root), "{path}");
+        }
+        for path in [
+            "../evil",
+            "serde-1.0.152/../../evil",
+            "serde-1.0.152/../serde_json-1.0.0/src/lib.rs",
+            "serde_json-1.0.0/src/lib.rs",
+            "serde-1.0.1520/src/lib.rs",
+            "lib.rs",
+            "/etc/passwd",
+        ] {
+            assert!(!is_safe_entry_path(Path::new(path), root), "{path}");
+        }
+
+        assert!(is_descending_path(Path::new("src/lib.rs")));
+        assert!(!is_descending_path(Path::new("../serde_json-1.0.0")));
+    }
+
+    #[test]
+    fn crate_entry_invalid_names() {
+        init_logging();
+
+        for full_name in ["foo-bar-baz", "serde", "-1.0.0", "base-64", "serde-1.0"] {
+            assert!(matches!(
+                CrateEntry::try_from(full_name),
+                Err(Error::CrateNameError(_))
+            ));
+        }
+    }
 }
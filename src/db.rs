@@ -1,5 +1,16 @@
+use crate::{
+    analysis::CallRecord,
+    stats::FunctionRecord,
+    store::{Dependency, GraphStore},
+};
 use neo4rs::{query, Graph, Node};
-use std::sync::Arc;
+use petgraph::graph::DiGraph;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -12,10 +23,183 @@ pub enum Error {
     InvalidSemver(String),
     #[error("Crate Invalid: {0}")]
     CrateNotFound(String),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite Error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite task failed: {0}")]
+    SqliteTaskError(#[from] tokio::task::JoinError),
+}
+impl Error {
+    /// Returns true if this is a transport failure with the database, which may succeed if the
+    /// operation is retried. Query and constraint errors are never transient.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Neo4jError(neo4rs::Error::IOError { .. } | neo4rs::Error::ConnectionError)
+        )
+    }
+}
+
+/// A `DEPENDS_ON` relationship from the latest version of a crate to a crate it depends on, as
+/// returned by `Db::dependency_graph`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DependencyEdge {
+    /// Name of the dependent crate.
+    pub src: String,
+    /// Name of the crate depended on.
+    pub dst: String,
+    /// The semver requirement of the dependency.
+    pub requirement: String,
+    /// The kind of dependency, one of `Normal`, `Build` or `Dev`.
+    pub kind: String,
+    /// Whether the dependency is only enabled by a feature.
+    pub optional: bool,
+}
+
+/// Configuration of retries of database writes on transient failures, see `Error::is_transient`.
+/// The delay between attempts starts at `initial_backoff` and doubles on every further attempt,
+/// up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Maximum number of attempts of an operation, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+}
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Parse the version of a crate into its semver components. Versions are first parsed strictly,
+/// and if that fails, fall back to splitting `major.minor.patch[-pre][+build]` by hand. Any
+/// non-numeric suffix directly following the patch number, such as in `1.0.0beta2`, is treated as
+/// the pre-release when no other pre-release is given. Versions without all three of major, minor
+/// and patch are invalid.
+///
+/// # Errors
+/// Returns `Error::InvalidSemver` if the version can not be parsed.
+pub(crate) fn parse_crate_semver(version: &str) -> Result<semver::Version, Error> {
+    if let Ok(s) = semver::Version::parse(version) {
+        return Ok(s);
+    }
+
+    let invalid = || Error::InvalidSemver(version.to_owned());
+
+    let (rest, build) = version.split_once('+').unwrap_or((version, ""));
+    let (core, pre) = rest.split_once('-').unwrap_or((rest, ""));
+
+    let split: Vec<_> = core.split('.').collect();
+    if split.len() != 3 {
+        return Err(invalid());
+    }
+
+    let patch_end = split[2]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(split[2].len());
+    let (patch, patch_suffix) = split[2].split_at(patch_end);
+    let pre = if pre.is_empty() {
+        patch_suffix.trim_start_matches('.')
+    } else {
+        pre
+    };
+
+    Ok(semver::Version {
+        major: split[0].parse::<u64>().map_err(|_| invalid())?,
+        minor: split[1].parse::<u64>().map_err(|_| invalid())?,
+        patch: patch.parse::<u64>().map_err(|_| invalid())?,
+        pre: semver::Prerelease::new(pre).map_err(|_| invalid())?,
+        build: semver::BuildMetadata::new(build).map_err(|_| invalid())?,
+    })
+}
+
+/// Convert a component of a parsed version to the integer type stored in the database.
+fn semver_part(part: u64, version: &str) -> Result<u32, Error> {
+    u32::try_from(part).map_err(|_| Error::InvalidSemver(version.to_owned()))
+}
+
+/// Build the query creating a single `[:INVOKES]` relationship, see `Db::insert_invoke`.
+#[allow(clippy::similar_names)]
+fn invoke_query(call: &CallRecord, src_crate: (&str, &str), dst_crate: &str) -> neo4rs::Query {
+    query(
+        "MATCH (srcVersion:Version { name: $src_crate, version: $src_version }) 
+            MATCH (dstCrate:Crate { name: $dst_crate }) 
+            CREATE (srcVersion)-[:INVOKES {callsite: $caller, target: $callee, callsite_symbol: $caller_symbol, target_symbol: $callee_symbol, kind: $kind}]->(dstCrate)
+        ",
+    )
+    .param("src_crate", src_crate.0)
+    .param("src_version", src_crate.1)
+    .param("dst_crate", dst_crate)
+    .param("caller", call.caller_demangled.as_str())
+    .param("callee", call.callee_demangled.as_str())
+    .param("caller_symbol", call.caller.as_str())
+    .param("callee_symbol", call.callee.as_str())
+    .param("kind", call.kind.as_str())
+}
+
+/// Build the query merging a single `(Function)` node, see `Db::insert_function`.
+fn function_query(src_crate: (&str, &str), function: &FunctionRecord) -> neo4rs::Query {
+    let metrics = &function.metrics;
+    query(
+        "MATCH (version:Version { name: $crate_name, version: $crate_version }) 
+            MERGE (version)-[:DEFINES]->(function:Function { symbol: $symbol }) 
+            SET function.name = $name, 
+                function.blocks = $blocks, 
+                function.cyclomatic_complexity = $cyclomatic_complexity, 
+                function.recursive = $recursive, 
+                function.call_depth = $call_depth
+        ",
+    )
+    .param("crate_name", src_crate.0)
+    .param("crate_version", src_crate.1)
+    .param("symbol", function.symbol.as_str())
+    .param("name", function.name.as_str())
+    .param("blocks", metrics.blocks)
+    .param("cyclomatic_complexity", metrics.cyclomatic_complexity)
+    .param("recursive", metrics.recursive)
+    .param("call_depth", metrics.call_depth)
+}
+
+/// Default number of rows fetched per round trip when streaming query results.
+pub const DEFAULT_FETCH_SIZE: usize = 200;
+/// Default size of the connection pool.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Connection configuration of the neo4j database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbConfig {
+    /// Name of the database to use within the instance.
+    pub database: String,
+    /// Number of rows fetched per round trip when streaming query results.
+    pub fetch_size: usize,
+    /// Maximum number of pooled connections.
+    pub max_connections: usize,
+    /// Retry behavior of writes on transient failures.
+    pub retry: RetryConfig,
+}
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            database: "neo4j".to_string(),
+            fetch_size: DEFAULT_FETCH_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            retry: RetryConfig::default(),
+        }
+    }
 }
 
 pub struct Db {
     conn: Arc<Graph>,
+    retry: RetryConfig,
 }
 impl Db {
     #[allow(clippy::must_use_candidate)]
@@ -23,12 +207,18 @@ impl Db {
         self.conn.clone()
     }
 
-    /// Connect to the neo4j database with the specified parameters.
+    /// Connect to the neo4j database with the specified parameters. Writes which fail due to
+    /// transient transport errors are retried according to `config.retry`.
     /// # Panics
     /// This function will panic if invalid parameters are provided in the configuration.
     /// # Errors
     /// This function will return an `painter::db::Error` in the event of a connection failure.
-    pub async fn connect<URI, U, P>(uri: URI, username: U, password: P) -> Result<Self, Error>
+    pub async fn connect<URI, U, P>(
+        uri: URI,
+        username: U,
+        password: P,
+        config: DbConfig,
+    ) -> Result<Self, Error>
     where
         URI: AsRef<str>,
         U: AsRef<str>,
@@ -40,15 +230,76 @@ impl Db {
                     .uri(uri.as_ref())
                     .user(username.as_ref())
                     .password(password.as_ref())
-                    .db("neo4j")
-                    .fetch_size(10)
-                    .max_connections(64)
+                    .db(config.database.as_str())
+                    .fetch_size(config.fetch_size)
+                    .max_connections(config.max_connections)
                     .build()
                     .unwrap(),
             )
             .await?,
         );
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            retry: config.retry,
+        })
+    }
+
+    /// Execute an operation, retrying it with exponential backoff while it fails with a transient
+    /// error and attempts remain.
+    async fn with_retry<T, F, Fut>(&self, op: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        let mut backoff = self.retry.initial_backoff;
+        loop {
+            match op().await {
+                Err(e) if e.is_transient() && attempt < self.retry.max_attempts => {
+                    log::warn!(
+                        "Transient database error, retrying in {:?} (attempt {}/{}): {}",
+                        backoff,
+                        attempt,
+                        self.retry.max_attempts,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Create the uniqueness constraints on `(Crate { name })` and `(Version { name, version })`,
+    /// along with the indexes used by lookups of the latest version and invocation targets.
+    /// Every statement is `IF NOT EXISTS`, so this is safe to call on an existing database.
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error, for
+    /// example if duplicate nodes already exist which violate a constraint.
+    pub async fn init_schema(&self) -> Result<(), Error> {
+        const SCHEMA: &[&str] = &[
+            "CREATE CONSTRAINT const_crate_name IF NOT EXISTS \
+             FOR (n:Crate) REQUIRE n.name IS UNIQUE",
+            "CREATE CONSTRAINT const_version_name IF NOT EXISTS \
+             FOR (n:Version) REQUIRE (n.name, n.version) IS UNIQUE",
+            "CREATE INDEX idx_is_latest IF NOT EXISTS FOR (n:Version) ON (n.latest)",
+            "CREATE TEXT INDEX idx_invoke_target IF NOT EXISTS \
+             FOR ()-[r:INVOKES]-() ON (r.target)",
+            "CREATE INDEX idx_function_name IF NOT EXISTS FOR (n:Function) ON (n.name)",
+            "CREATE INDEX idx_function_symbol IF NOT EXISTS FOR (n:Function) ON (n.symbol)",
+        ];
+
+        for statement in SCHEMA {
+            log::trace!("{}", statement);
+            self.conn.run(query(statement)).await?;
+        }
+
+        Ok(())
     }
 
     /// Insert a new function invocation into the database. This creates the specified relationship
@@ -58,7 +309,10 @@ impl Db {
     ///
     /// This may change in the future where we can specify a range of versions for an invocation.
     ///
-    /// `(Version)-[:INVOKES { caller, callee }]->(Crate)`
+    /// The demangled names are stored as `callsite` and `target`, alongside the raw mangled symbols
+    /// and whether the call is direct or through a function pointer.
+    ///
+    /// `(Version)-[:INVOKES { callsite, target, callsite_symbol, target_symbol, kind }]->(Crate)`
     ///
     /// # Panics
     /// This function should not panic.
@@ -67,39 +321,188 @@ impl Db {
     #[allow(clippy::similar_names)]
     pub async fn insert_invoke(
         &self,
-        caller: &str,
-        callee: &str,
+        call: &CallRecord,
         src_crate: (&str, &str),
         dst_crate: &str,
     ) -> Result<(), Error> {
-        self
-            .conn
-            .execute(
+        self.with_retry(|| async move {
+            self.conn
+                .execute(invoke_query(call, src_crate, dst_crate))
+                .await?
+                .next()
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete all `[:INVOKES]` relationships of a crate version, leaving the version itself and its
+    /// dependencies in place.
+    ///
+    /// `(Version { name, version })-[:INVOKES]->(Crate)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn clear_invokes(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            self.conn
+                .run(
+                    query("MATCH (v:Version { name: $name, version: $version })-[r:INVOKES]->() DELETE r")
+                        .param("name", name)
+                        .param("version", version),
+                )
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Insert all invocations of a crate version within a single transaction, so either all of its
+    /// calls are stored or none are. An interrupted export never leaves a partially imported crate
+    /// which `has_any_invoke` would report as done. The invoked crate of each call is taken from
+    /// `CallRecord::callee_crate`.
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn insert_invokes(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run_queries(
+                calls
+                    .iter()
+                    .map(|call| invoke_query(call, src_crate, call.callee_crate()))
+                    .collect(),
+            )
+            .await?;
+
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Insert, or update, the metrics of a function defined by a crate version. Functions are
+    /// identified by their mangled symbol within the version, so each monomorphization is stored
+    /// as its own function, with the demangled name they share as a separate property.
+    ///
+    /// `(Version)-[:DEFINES]->(Function { symbol, name, blocks, cyclomatic_complexity, recursive, call_depth })`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a database error.
+    pub async fn insert_function(
+        &self,
+        src_crate: (&str, &str),
+        function: &FunctionRecord,
+    ) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            self.conn.run(function_query(src_crate, function)).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Insert, or update, the metrics of all given functions of a crate version within a single
+    /// transaction, see `insert_function`.
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a database error.
+    pub async fn insert_functions(
+        &self,
+        src_crate: (&str, &str),
+        functions: &[FunctionRecord],
+    ) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run_queries(
+                functions
+                    .iter()
+                    .map(|function| function_query(src_crate, function))
+                    .collect(),
+            )
+            .await?;
+
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replace all invocations of a crate version with the given calls. The existing `[:INVOKES]`
+    /// relationships are cleared and the new ones inserted within a single transaction, so a failed
+    /// re-import leaves the previous invocations intact. The invoked crate of each call is taken
+    /// from `CallRecord::callee_crate`.
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn reimport_crate(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run(
                 query(
-                    "MATCH (srcVersion:Version { name: $src_crate, version: $src_version }) 
-                        MATCH (dstCrate:Crate { name: $dst_crate }) 
-                        CREATE (srcVersion)-[:INVOKES {callsite: $caller, target: $callee}]->(dstCrate)
-                    ",
+                    "MATCH (v:Version { name: $name, version: $version })-[r:INVOKES]->() DELETE r",
                 )
-                .param("src_crate", src_crate.0)
-                .param("src_version", src_crate.1)
-                .param("dst_crate", dst_crate)
-                .param("caller", caller)
-                .param("callee", callee),
+                .param("name", src_crate.0)
+                .param("version", src_crate.1),
+            )
+            .await?;
+
+            tx.run_queries(
+                calls
+                    .iter()
+                    .map(|call| invoke_query(call, src_crate, call.callee_crate()))
+                    .collect(),
             )
-            .await?.next().await?;
+            .await?;
 
-        Ok(())
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
     }
 
     /// Insert a new version of a crate into the database. This will create a new `(Version)` node,
     /// linking it to its associated top-level `(Crate)` node. If that node does not exist, it is created.
     ///
+    /// The version node and all of its relationships are merged, so inserting a version which
+    /// already exists does not create duplicate nodes or relationships. Only `yanked` is updated on
+    /// an existing version, as the other properties are derived from its version string.
+    ///
     /// Also inserts all dependency relationships for this version of the crate; whatever is specified
     /// in the Cargo.toml for this version as its dependencies will gain `[:DEPENDS_ON]` relationships
     /// with other `(Crate)` nodes. We do not specify exact version-version `[:DEPENDS_ON]` relationships,
     /// because semver may cause these versions to shift and change based on build time and release
-    /// cycles.
+    /// cycles. Exact relationships for a snapshot of the index can be added separately with
+    /// `insert_resolved_dependencies`.
+    ///
+    /// Whether the version has been yanked from the registry is stored as `yanked` on the
+    /// `(Version)` node.
     ///
     /// `(Version)-[:DEPENDS_ON {requirement, features, kind, optional}]->(Crate)`
     ///
@@ -113,6 +516,7 @@ impl Db {
         &self,
         name: &str,
         version: &str,
+        yanked: bool,
         depends_on: I,
     ) -> Result<(), Error>
     where
@@ -123,79 +527,120 @@ impl Db {
         S4: AsRef<str> + 'a,
         S5: AsRef<str> + 'a,
     {
-        let semver = if let Ok(s) = lenient_semver::parse(version) {
-            s
-        } else {
-            let split: Vec<_> = version.split('.').collect();
-            let patch = split[2]
-                .chars()
-                .filter(char::is_ascii_digit)
-                .collect::<String>()
-                .parse::<u64>()
-                .map_err(|_| Error::InvalidSemver(version.to_owned()))?;
-
-            semver::Version {
-                major: split[0]
-                    .parse::<u64>()
-                    .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                minor: split[1]
-                    .parse::<u64>()
-                    .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                patch,
-                build: semver::BuildMetadata::default(),
-                pre: semver::Prerelease::default(),
-            }
-        };
+        let semver = parse_crate_semver(version)?;
 
-        let version_id = {
+        let semver_major = semver_part(semver.major, version)?;
+        let semver_minor = semver_part(semver.minor, version)?;
+        let semver_patch = semver_part(semver.patch, version)?;
+        let semver_build = semver.build.as_str();
+        let semver_pre = semver.pre.as_str();
+
+        let version_id = self.with_retry(|| async move {
             let mut result = self.conn
             .execute(
                 query(
                     "MERGE (crate:Crate { name: $name }) 
-                     CREATE (version:Version {name: $name, version: $version, semver_major: toInteger($semver_major), semver_minor: toInteger($semver_minor), semver_patch: toInteger($semver_patch), semver_build: $semver_build, semver_pre: $semver_pre })
-                     CREATE (version)-[:VERSION_OF]->(crate)
+                     MERGE (version:Version {name: $name, version: $version })
+                     ON CREATE SET version.semver_major = toInteger($semver_major), version.semver_minor = toInteger($semver_minor), version.semver_patch = toInteger($semver_patch), version.semver_build = $semver_build, version.semver_pre = $semver_pre
+                     SET version.yanked = $yanked
+                     MERGE (version)-[:VERSION_OF]->(crate)
                      RETURN version",
                 )
                 .param("name", name)
                 .param("version", version)
-                .param("semver_major", u32::try_from(semver.major).map_err(|_| Error::InvalidSemver(version.to_owned()))?)
-                    .param("semver_minor", u32::try_from(semver.minor).map_err(|_| Error::InvalidSemver(version.to_owned()))?)
-                    .param("semver_patch", u32::try_from(semver.patch).map_err(|_| Error::InvalidSemver(version.to_owned()))?)
-                    .param("semver_build", semver.build.as_str())
-                    .param("semver_pre", semver.pre.as_str())
+                .param("semver_major", semver_major)
+                    .param("semver_minor", semver_minor)
+                    .param("semver_patch", semver_patch)
+                    .param("semver_build", semver_build)
+                    .param("semver_pre", semver_pre)
+                    .param("yanked", yanked)
             )
             .await?;
 
             let version_node: Node = result
                 .next()
-                .await
-                .unwrap()
+                .await?
                 .unwrap()
                 .get("version")
                 .unwrap();
 
-            version_node.id()
-        };
+            Ok(version_node.id())
+        })
+        .await?;
 
-        let tx = self.conn.start_txn().await.unwrap();
+        let depends_on: Vec<_> = depends_on.collect();
+        let depends_on = &depends_on;
 
-        tx.run_queries(depends_on.into_iter().map(|depend| {
-            query(
-                "MATCH (version:Version) WHERE ID(version) = $version_id
-                         MERGE (depend:Crate { name: $depend })
-                         CREATE (version)-[:DEPENDS_ON { requirement: $req, features: $features, kind: $kind, optional: toBoolean($optional) } ]->(depend)",
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run_queries(depends_on.iter().map(|depend| {
+                query(
+                    "MATCH (version:Version) WHERE ID(version) = $version_id
+                             MERGE (depend:Crate { name: $depend })
+                             MERGE (version)-[:DEPENDS_ON { requirement: $req, features: $features, kind: $kind, optional: toBoolean($optional) } ]->(depend)",
+                )
+                    .param("version_id", version_id)
+                    .param("depend", depend.0.as_ref())
+                    .param("req", depend.1.as_ref())
+                    .param("features", depend.2.as_ref())
+                    .param("kind", depend.3.as_ref())
+                    .param("optional", depend.4.as_ref())
+            }).collect()).await?;
+
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Insert exact dependency relationships between two crate versions, as resolved against the
+    /// versions published at the time of resolution. Each entry of `resolved` is the
+    /// `(name, version)` of a dependency of `src_crate`. Unlike the `(Version)-[:DEPENDS_ON]->(Crate)`
+    /// relationships from `insert_crate_version`, these only hold for a frozen snapshot of the index,
+    /// as a newer publish may change which version a requirement resolves to.
+    ///
+    /// Existing relationships are not duplicated, and dependencies whose version does not exist in
+    /// the database are skipped.
+    ///
+    /// `(Version)-[:DEPENDS_ON]->(Version)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn insert_resolved_dependencies(
+        &self,
+        src_crate: (&str, &str),
+        resolved: &[(String, String)],
+    ) -> Result<(), Error> {
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run_queries(
+                resolved
+                    .iter()
+                    .map(|(depend, depend_version)| {
+                        query(
+                            "MATCH (version:Version { name: $name, version: $version }) \
+                             MATCH (depend:Version { name: $depend, version: $depend_version }) \
+                             MERGE (version)-[:DEPENDS_ON]->(depend)",
+                        )
+                        .param("name", src_crate.0)
+                        .param("version", src_crate.1)
+                        .param("depend", depend.as_str())
+                        .param("depend_version", depend_version.as_str())
+                    })
+                    .collect(),
             )
-                .param("version_id", version_id)
-                .param("depend", depend.0.as_ref())
-                .param("req", depend.1.as_ref())
-                .param("features", depend.2.as_ref())
-                .param("kind", depend.3.as_ref())
-                .param("optional", depend.4.as_ref())
-        }).collect()).await?;
+            .await?;
 
-        tx.commit().await?;
+            tx.commit().await?;
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 
     /// Upserts a new function invocation into the database. This creates the specified relationship
@@ -253,6 +698,9 @@ impl Db {
     ///
     /// If the current relationship already exists, a new one will *not* be created, hence the upsert.
     ///
+    /// Whether the version has been yanked from the registry is stored as `yanked` on the
+    /// `(Version)` node.
+    ///
     /// `(Version)-[:DEPENDS_ON {requirement, features, kind, optional}]->(Crate)`
     ///
     /// # Panics
@@ -261,10 +709,12 @@ impl Db {
     /// a new Node is not returned during insertion.
     /// # Errors
     /// This function will return an `painter::db::Error` in the event of a database error.
+    #[deprecated(note = "`insert_crate_version` merges existing versions, use it instead")]
     pub async fn upsert_crate_version<'a, I, S1, S2, S3, S4, S5>(
         &self,
         name: &str,
         version: &str,
+        yanked: bool,
         depends_on: I,
     ) -> Result<(), Error>
     where
@@ -275,29 +725,7 @@ impl Db {
         S4: AsRef<str> + 'a,
         S5: AsRef<str> + 'a,
     {
-        let semver = if let Ok(s) = lenient_semver::parse(version) {
-            s
-        } else {
-            let split: Vec<_> = version.split('.').collect();
-            let patch = split[2]
-                .chars()
-                .filter(char::is_ascii_digit)
-                .collect::<String>()
-                .parse::<u64>()
-                .map_err(|_| Error::InvalidSemver(version.to_owned()))?;
-
-            semver::Version {
-                major: split[0]
-                    .parse::<u64>()
-                    .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                minor: split[1]
-                    .parse::<u64>()
-                    .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                patch,
-                build: semver::BuildMetadata::default(),
-                pre: semver::Prerelease::default(),
-            }
-        };
+        let semver = parse_crate_semver(version)?;
 
         let version_id = {
             let mut result = self
@@ -312,27 +740,17 @@ impl Db {
                      semver_build: $semver_build, 
                      semver_pre: $semver_pre })
                      MERGE (version)-[:VERSION_OF]->(crate)
+                     SET version.yanked = $yanked
                      RETURN version",
                     )
                     .param("name", name)
                     .param("version", version)
-                    .param(
-                        "semver_major",
-                        u32::try_from(semver.major)
-                            .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                    )
-                    .param(
-                        "semver_minor",
-                        u32::try_from(semver.minor)
-                            .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                    )
-                    .param(
-                        "semver_patch",
-                        u32::try_from(semver.patch)
-                            .map_err(|_| Error::InvalidSemver(version.to_owned()))?,
-                    )
+                    .param("semver_major", semver_part(semver.major, version)?)
+                    .param("semver_minor", semver_part(semver.minor, version)?)
+                    .param("semver_patch", semver_part(semver.patch, version)?)
                     .param("semver_build", semver.build.as_str())
-                    .param("semver_pre", semver.pre.as_str()),
+                    .param("semver_pre", semver.pre.as_str())
+                    .param("yanked", yanked),
                 )
                 .await?;
 
@@ -415,37 +833,41 @@ impl Db {
             .is_some())
     }
 
+    /// Mark the given version as the only latest version of a crate, clearing `latest` on all of
+    /// its other versions. Both updates run within a single transaction, so a crate is never left
+    /// without a latest version. Which version is the latest is chosen by the caller, see
+    /// `painter::index::latest_version`.
     ///
-    /// # Panics
+    /// `(Version { name, version, latest })`
     ///
+    /// # Panics
+    /// This function should not panic.
     /// # Errors
-    ///
+    /// This function will return an `painter::db::Error` in the event of a database error.
     pub async fn set_latest<S1, S2>(&self, name: S1, version: S2) -> Result<(), Error>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        // Clear all other latest for this name
-        self.conn
-            .execute(
-                query("MATCH (v:Version {name: $name }) SET v.latest = False")
-                    .param("name", name.as_ref()),
-            )
-            .await?
-            .next()
-            .await?;
+        let name = name.as_ref();
+        let version = version.as_ref();
 
-        self.conn
-            .execute(
+        self.with_retry(|| async move {
+            let tx = self.conn.start_txn().await?;
+
+            tx.run_queries(vec![
+                query("MATCH (v:Version {name: $name }) SET v.latest = False").param("name", name),
                 query("MATCH (v:Version {name: $name, version: $version }) SET v.latest = True")
-                    .param("name", name.as_ref())
-                    .param("version", version.as_ref()),
-            )
-            .await?
-            .next()
+                    .param("name", name)
+                    .param("version", version),
+            ])
             .await?;
 
-        Ok(())
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
     }
 
     ///
@@ -511,11 +933,15 @@ impl Db {
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        if self
-            .conn
-            .execute(
-                query(
-                    "MATCH (v:Version {name:  $name, version: $version}) SET \
+        let name = name.as_ref();
+        let version = version.as_ref();
+
+        self.with_retry(|| async move {
+            if self
+                .conn
+                .execute(
+                    query(
+                        "MATCH (v:Version {name:  $name, version: $version}) SET \
                 v.unsafe_total = $unsafe_total, \
                 v.unsafe_functions = $unsafe_functions, \
                 v.unsafe_exprs = $unsafe_exprs, \
@@ -528,29 +954,425 @@ impl Db {
                 v.safe_traits = $safe_traits, \
                 v.safe_methods = $safe_methods \
                 RETURN v",
+                    )
+                    .param("name", name)
+                    .param("version", version)
+                    .param("unsafe_total", unsafe_result.total_unsafe())
+                    .param("unsafe_functions", unsafe_result.functions.unsafe_)
+                    .param("unsafe_exprs", unsafe_result.exprs.unsafe_)
+                    .param("unsafe_impls", unsafe_result.item_impls.unsafe_)
+                    .param("unsafe_traits", unsafe_result.item_traits.unsafe_)
+                    .param("unsafe_methods", unsafe_result.methods.unsafe_)
+                    .param("safe_functions", unsafe_result.functions.safe)
+                    .param("safe_exprs", unsafe_result.exprs.safe)
+                    .param("safe_impls", unsafe_result.item_impls.safe)
+                    .param("safe_traits", unsafe_result.item_traits.safe)
+                    .param("safe_methods", unsafe_result.methods.safe),
                 )
-                .param("name", name.as_ref())
-                .param("version", version.as_ref())
-                .param("unsafe_total", unsafe_result.total_unsafe())
-                .param("unsafe_functions", unsafe_result.functions.unsafe_)
-                .param("unsafe_exprs", unsafe_result.exprs.unsafe_)
-                .param("unsafe_impls", unsafe_result.item_impls.unsafe_)
-                .param("unsafe_traits", unsafe_result.item_traits.unsafe_)
-                .param("unsafe_methods", unsafe_result.methods.unsafe_)
-                .param("safe_functions", unsafe_result.functions.safe)
-                .param("safe_exprs", unsafe_result.exprs.safe)
-                .param("safe_impls", unsafe_result.item_impls.safe)
-                .param("safe_traits", unsafe_result.item_traits.safe)
-                .param("safe_methods", unsafe_result.methods.safe),
+                .await?
+                .next()
+                .await?
+                .is_none()
+            {
+                Err(Error::CrateNotFound(name.to_string()))
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Returns the names of all crates which have any version depending on the given crate.
+    ///
+    /// `(Version)-[:DEPENDS_ON]->(Crate { name })`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn reverse_dependencies(&self, name: &str) -> Result<Vec<String>, Error> {
+        self.query_names(
+            query(
+                "MATCH (v:Version)-[:DEPENDS_ON]->(c:Crate {name: $name}) \
+                 RETURN DISTINCT v.name AS name",
             )
-            .await?
-            .next()
-            .await?
-            .is_none()
-        {
-            Err(Error::CrateNotFound(name.as_ref().to_string()))
-        } else {
-            Ok(())
+            .param("name", name),
+        )
+        .await
+    }
+
+    /// Returns the names of all crates which have any version depending on the given crate, and
+    /// which also have an invocation into it. This narrows `reverse_dependencies` to the crates
+    /// actually calling into the dependency rather than only declaring it.
+    ///
+    /// `(Version)-[:DEPENDS_ON]->(Crate { name })<-[:INVOKES]-(Version)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn dependents_invoking(&self, name: &str) -> Result<Vec<String>, Error> {
+        self.query_names(
+            query(
+                "MATCH (v:Version)-[:DEPENDS_ON]->(c:Crate {name: $name}) \
+                 WHERE (v)-[:INVOKES]->(c) \
+                 RETURN DISTINCT v.name AS name",
+            )
+            .param("name", name),
+        )
+        .await
+    }
+
+    /// Returns every crate version invoking a function whose name contains the given string, as
+    /// `(src_crate, src_version, caller)` tuples.
+    ///
+    /// Invocations are stored with demangled names in their alternate form, without the trailing
+    /// hash, e.g. `serde_json::de::from_str` rather than `_ZN10serde_json2de8from_str17h...E`.
+    /// Searching for a full path will match that function along with any of its monomorphizations
+    /// and closures, while a module path such as `serde_json::de::` matches all functions within it.
+    ///
+    /// `(Version)-[:INVOKES { target }]->(Crate)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn callers_of_function(
+        &self,
+        callee_substring: &str,
+    ) -> Result<Vec<(String, String, String)>, Error> {
+        let mut result = self
+            .conn
+            .execute(
+                query(
+                    "MATCH (v:Version)-[r:INVOKES]->() \
+                     WHERE r.target CONTAINS $callee \
+                     RETURN v.name AS name, v.version AS version, r.callsite AS caller",
+                )
+                .param("callee", callee_substring),
+            )
+            .await?;
+
+        let mut callers = Vec::new();
+        while let Some(row) = result.next().await? {
+            if let (Some(name), Some(version), Some(caller)) = (
+                row.get::<String>("name"),
+                row.get::<String>("version"),
+                row.get::<String>("caller"),
+            ) {
+                callers.push((name, version, caller));
+            }
+        }
+
+        Ok(callers)
+    }
+
+    /// Returns every yanked crate version, as `(name, version)` tuples.
+    ///
+    /// `(Version { yanked: true })`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn yanked_versions(&self) -> Result<Vec<(String, String)>, Error> {
+        self.query_versions(query(
+            "MATCH (v:Version { yanked: true }) RETURN v.name AS name, v.version AS version",
+        ))
+        .await
+    }
+
+    /// Returns every crate version which could transitively reach a vulnerable function, as
+    /// `(name, version)` tuples. This starts at the versions invoking the function, matched by its
+    /// demangled name as stored in `Db::insert_invoke` along with any of its closures, and walks
+    /// resolved `(Version)-[:DEPENDS_ON]->(Version)` dependencies backwards up to `max_depth`
+    /// hops. A dependent is only followed if it also invokes the crate it depends on, so versions
+    /// which declare a dependency without calling into it are not reported.
+    ///
+    /// Only the direct callers are found unless dependencies have been resolved with
+    /// `index::insert_resolved_dependencies`.
+    ///
+    /// `(Version)-[:DEPENDS_ON*0..max_depth]->(Version)-[:INVOKES { target }]->(Crate)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn taint_reachable(
+        &self,
+        vulnerable_fn: &str,
+        max_depth: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        // Variable length bounds can not be parameters, so the depth is formatted into the query
+        self.query_versions(
+            query(&format!(
+                "MATCH (caller:Version)-[r:INVOKES]->() \
+                 WHERE r.target = $function OR r.target STARTS WITH $closures \
+                 WITH DISTINCT caller \
+                 MATCH p = (v:Version)-[:DEPENDS_ON*0..{max_depth}]->(caller) \
+                 WHERE all(d IN relationships(p) WHERE EXISTS {{ \
+                     MATCH (dependent:Version)-[:INVOKES]->(:Crate {{ name: endNode(d).name }}) \
+                     WHERE dependent = startNode(d) \
+                 }}) \
+                 RETURN DISTINCT v.name AS name, v.version AS version"
+            ))
+            .param("function", vulnerable_fn)
+            .param("closures", format!("{vulnerable_fn}::{{{{closure}}}}")),
+        )
+        .await
+    }
+
+    /// Returns the `DEPENDS_ON` edges reachable from the latest version of a crate within `depth`
+    /// hops, following the latest version of each dependency in turn. Dev-dependencies are only
+    /// reported for `name` itself, as those of its dependencies are never built along with it.
+    /// Optional dependencies are followed, and marked with `DependencyEdge::optional`.
+    ///
+    /// `(Version { latest: true })-[:DEPENDS_ON]->(Crate)`
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn dependency_graph(
+        &self,
+        name: &str,
+        depth: usize,
+    ) -> Result<Vec<DependencyEdge>, Error> {
+        let mut edges = Vec::new();
+        let mut visited = HashSet::from([name.to_string()]);
+        let mut frontier = vec![name.to_string()];
+
+        for hop in 0..depth {
+            if frontier.is_empty() {
+                break;
+            }
+
+            let mut result = self
+                .conn
+                .execute(
+                    query(
+                        "UNWIND $names AS name \
+                         MATCH (v:Version { name: name, latest: true })-[d:DEPENDS_ON]->(c:Crate) \
+                         RETURN v.name AS src, c.name AS dst, d.requirement AS requirement, \
+                         d.kind AS kind, d.optional AS optional",
+                    )
+                    .param("names", std::mem::take(&mut frontier)),
+                )
+                .await?;
+
+            while let Some(row) = result.next().await? {
+                let (Some(src), Some(dst), Some(requirement), Some(kind)) = (
+                    row.get::<String>("src"),
+                    row.get::<String>("dst"),
+                    row.get::<String>("requirement"),
+                    row.get::<String>("kind"),
+                ) else {
+                    continue;
+                };
+                if hop > 0 && kind == "Dev" {
+                    continue;
+                }
+
+                if visited.insert(dst.clone()) {
+                    frontier.push(dst.clone());
+                }
+                edges.push(DependencyEdge {
+                    src,
+                    dst,
+                    requirement,
+                    kind,
+                    optional: row.get::<bool>("optional").unwrap_or(false),
+                });
+            }
         }
+
+        Ok(edges)
+    }
+
+    /// Returns the dependency graph of `Db::dependency_graph` as a `petgraph` graph, with a node
+    /// for each crate name and an edge for each dependency.
+    ///
+    /// # Panics
+    /// This function should not panic.
+    /// # Errors
+    /// This function will return an `painter::db::Error` in the event of a database error.
+    pub async fn dependency_petgraph(
+        &self,
+        name: &str,
+        depth: usize,
+    ) -> Result<DiGraph<String, DependencyEdge>, Error> {
+        let mut graph = DiGraph::new();
+        let mut nodes = HashMap::new();
+
+        for edge in self.dependency_graph(name, depth).await? {
+            let src = *nodes
+                .entry(edge.src.clone())
+                .or_insert_with(|| graph.add_node(edge.src.clone()));
+            let dst = *nodes
+                .entry(edge.dst.clone())
+                .or_insert_with(|| graph.add_node(edge.dst.clone()));
+            graph.add_edge(src, dst, edge);
+        }
+
+        Ok(graph)
+    }
+
+    /// Execute a query returning rows with `name` and `version` columns, collecting them.
+    async fn query_versions(&self, q: neo4rs::Query) -> Result<Vec<(String, String)>, Error> {
+        let mut result = self.conn.execute(q).await?;
+
+        let mut versions = Vec::new();
+        while let Some(row) = result.next().await? {
+            if let (Some(name), Some(version)) =
+                (row.get::<String>("name"), row.get::<String>("version"))
+            {
+                versions.push((name, version));
+            }
+        }
+
+        Ok(versions)
+    }
+
+    /// Execute a query returning rows with a single `name` column, collecting them.
+    async fn query_names(&self, q: neo4rs::Query) -> Result<Vec<String>, Error> {
+        let mut result = self.conn.execute(q).await?;
+
+        let mut names = Vec::new();
+        while let Some(row) = result.next().await? {
+            if let Some(name) = row.get::<String>("name") {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+}
+
+#[async_trait::async_trait]
+impl GraphStore for Db {
+    async fn init_schema(&self) -> Result<(), Error> {
+        Db::init_schema(self).await
+    }
+
+    async fn insert_invoke(
+        &self,
+        call: &CallRecord,
+        src_crate: (&str, &str),
+        dst_crate: &str,
+    ) -> Result<(), Error> {
+        Db::insert_invoke(self, call, src_crate, dst_crate).await
+    }
+
+    async fn insert_invokes(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        Db::insert_invokes(self, src_crate, calls).await
+    }
+
+    async fn clear_invokes(&self, name: &str, version: &str) -> Result<(), Error> {
+        Db::clear_invokes(self, name, version).await
+    }
+
+    async fn reimport_crate(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        Db::reimport_crate(self, src_crate, calls).await
+    }
+
+    async fn insert_crate_version(
+        &self,
+        name: &str,
+        version: &str,
+        yanked: bool,
+        depends_on: &[Dependency],
+    ) -> Result<(), Error> {
+        Db::insert_crate_version(self, name, version, yanked, depends_on.iter()).await
+    }
+
+    async fn insert_resolved_dependencies(
+        &self,
+        src_crate: (&str, &str),
+        resolved: &[(String, String)],
+    ) -> Result<(), Error> {
+        Db::insert_resolved_dependencies(self, src_crate, resolved).await
+    }
+
+    async fn crate_exists(&self, name: &str) -> Result<bool, Error> {
+        Db::crate_exists(self, name).await
+    }
+
+    async fn version_exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        Db::version_exists(self, name, version).await
+    }
+
+    async fn has_any_invoke(&self, name: &str, version: &str) -> Result<bool, Error> {
+        Db::has_any_invoke(self, name, version).await
+    }
+
+    async fn set_latest(&self, name: &str, version: &str) -> Result<(), Error> {
+        Db::set_latest(self, name, version).await
+    }
+
+    async fn insert_function(
+        &self,
+        src_crate: (&str, &str),
+        function: &FunctionRecord,
+    ) -> Result<(), Error> {
+        Db::insert_function(self, src_crate, function).await
+    }
+
+    async fn insert_functions(
+        &self,
+        src_crate: (&str, &str),
+        functions: &[FunctionRecord],
+    ) -> Result<(), Error> {
+        Db::insert_functions(self, src_crate, functions).await
+    }
+
+    async fn set_unsafe(
+        &self,
+        name: &str,
+        version: &str,
+        unsafe_result: &crate::analysis::CountUnsafeResult,
+    ) -> Result<(), Error> {
+        Db::set_unsafe(self, name, version, unsafe_result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_crate_semver_valid() {
+        let v = parse_crate_semver("1.2.3").unwrap();
+        assert_eq!((v.major, v.minor, v.patch), (1, 2, 3));
+        assert!(v.pre.is_empty());
+        assert!(v.build.is_empty());
+    }
+
+    #[test]
+    fn parse_crate_semver_pre_and_build() {
+        let v = parse_crate_semver("1.2.3-alpha.1+build.5").unwrap();
+        assert_eq!((v.major, v.minor, v.patch), (1, 2, 3));
+        assert_eq!(v.pre.as_str(), "alpha.1");
+        assert_eq!(v.build.as_str(), "build.5");
+    }
+
+    #[test]
+    fn parse_crate_semver_patch_suffix() {
+        let v = parse_crate_semver("0.1.0beta2").unwrap();
+        assert_eq!((v.major, v.minor, v.patch), (0, 1, 0));
+        assert_eq!(v.pre.as_str(), "beta2");
+    }
+
+    #[test]
+    fn parse_crate_semver_invalid() {
+        assert!(parse_crate_semver("not-a-version").is_err());
+        assert!(parse_crate_semver("1.2").is_err());
     }
 }
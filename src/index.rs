@@ -1,6 +1,10 @@
-use crate::db::Db;
+use crate::store::{Dependency, GraphStore};
 use crates_index::Crate;
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -8,58 +12,212 @@ pub enum Error {
     IndexError(#[from] crates_index::Error),
     #[error("{0}")]
     DatabaseError(#[from] crate::db::Error),
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
 }
 
+/// Number of crates between progress reports of `update_missing_versions`.
+const PROGRESS_INTERVAL: usize = 1024;
+
+/// Selects the crates of the index which are processed by a run, for working on a subset of the
+/// index. The default filter matches every crate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrateFilter {
+    /// If set, only crates named in this set are matched.
+    pub only: Option<HashSet<String>>,
+    /// Crates named in this set are never matched.
+    pub skip: HashSet<String>,
+    /// If set, only crates whose name starts with this prefix are matched.
+    pub prefix: Option<String>,
+}
+impl CrateFilter {
+    /// Build a filter from an allowlist file, a denylist file and a name prefix, each optional.
+    /// Files list one crate name per line, see `CrateFilter::parse_names`.
+    ///
+    /// # Errors
+    /// Returns `Error::IoError` if either file can not be read.
+    pub fn new(
+        only: Option<&Path>,
+        skip: Option<&Path>,
+        prefix: Option<String>,
+    ) -> Result<Self, Error> {
+        let read_names = |path: &Path| -> Result<_, Error> {
+            Ok(Self::parse_names(&std::fs::read_to_string(path)?))
+        };
+
+        Ok(Self {
+            only: only.map(read_names).transpose()?,
+            skip: skip.map(read_names).transpose()?.unwrap_or_default(),
+            prefix,
+        })
+    }
+
+    /// Parse a list of crate names, one per line. Surrounding whitespace, empty lines and lines
+    /// starting with `#` are ignored.
+    #[must_use]
+    pub fn parse_names(contents: &str) -> HashSet<String> {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /// Returns true if the crate named `name` should be processed.
+    #[must_use]
+    pub fn matches(&self, name: &str) -> bool {
+        self.only.as_ref().map_or(true, |only| only.contains(name))
+            && !self.skip.contains(name)
+            && self
+                .prefix
+                .as_ref()
+                .map_or(true, |prefix| name.starts_with(prefix.as_str()))
+    }
+}
+
+/// Insert all crates and versions from the index which are missing in the database. Crates which
+/// do not exist at all are inserted with all of their versions, while for existing crates only
+/// the missing versions are inserted.
+///
+/// Progress is logged periodically as the crates processed out of the total matching `filter`.
+/// If a `checkpoint` file is given, the name of the last processed crate is written to it after
+/// every chunk, and an existing checkpoint causes all crates up to and including that name to be
+/// skipped. The index is iterated in a stable order, so restarting with the same checkpoint
+/// resumes where the previous run stopped. If the checkpoint names a crate which is no longer in
+/// the index, a warning is logged and all crates are processed. Only crates matching `filter` are
+/// processed, so a checkpoint should be reused with the same filter.
 ///
 /// # Panics
-/// asdf
+/// This function should not panic.
 /// # Errors
-/// asdf
-pub async fn update_missing_versions(conn: Arc<Db>) -> Result<(), Error> {
+/// Returns an `painter::index::Error` if the index can not be opened or the checkpoint can not
+/// be read or written. Failures of individual crates are logged and do not stop the update.
+pub async fn update_missing_versions(
+    conn: Arc<dyn GraphStore>,
+    checkpoint: Option<&Path>,
+    filter: &CrateFilter,
+) -> Result<(), Error> {
     let index = crates_index::Index::new_cargo_default()?;
 
-    let do_crate = |c: Crate, db: Arc<Db>| async move {
-        if let Ok(res) = db.crate_exists(c.name()).await {
-            if res {
-                //log::info!("Missing crate: {}", c.name());
-                //if let Err(e) = insert_fresh_crate(c.clone(), db.clone()).await {
-                //    log::error!("Failed crate: {}", c.name());
-                //    log::error!("Failed crate: {}", e);
-                //}
+    let resume_after = match checkpoint {
+        Some(path) if path.exists() => Some(std::fs::read_to_string(path)?.trim().to_string()),
+        _ => None,
+    };
 
+    let do_crate = |c: Crate, db: Arc<dyn GraphStore>| async move {
+        match db.crate_exists(c.name()).await {
+            Ok(false) => {
+                log::info!("Missing crate: {}", c.name());
+                if let Err(e) = insert_fresh_crate(c.clone(), db.clone()).await {
+                    log::error!("Failed crate {}: {}", c.name(), e);
+                }
+            }
+            Ok(true) => {
                 for v in c.versions() {
-                    if let Ok(res) = db.version_exists(v.name(), v.version()).await {
-                        if !res {
-                            log::info!("Missing version: {}-{}", v.name(), v.version());
+                    match db.version_exists(v.name(), v.version()).await {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::error!(
+                                "Failed checking version {}-{}: {}",
+                                v.name(),
+                                v.version(),
+                                e
+                            );
+                            continue;
                         }
                     }
+
+                    log::info!("Missing version: {}-{}", v.name(), v.version());
+                    if let Err(e) = db
+                        .insert_crate_version(
+                            v.name(),
+                            v.version(),
+                            v.is_yanked(),
+                            &version_dependencies(v),
+                        )
+                        .await
+                    {
+                        log::error!("Failed version {}-{}: {}", v.name(), v.version(), e);
+                    }
                 }
             }
+            Err(e) => log::error!("Failed checking crate {}: {}", c.name(), e),
         }
     };
 
-    let iter = index.crates().array_chunks::<128>();
-    for chunk in iter {
+    let matches = |c: &Crate| filter.matches(c.name());
+    // Counted up front so progress can be reported against the whole run
+    let total = index.crates().filter(matches).count();
+    let mut processed = 0;
+
+    let mut crates = index.crates().filter(matches);
+    if let Some(name) = &resume_after {
+        if crates.by_ref().any(|c| {
+            processed += 1;
+            c.name() == name
+        }) {
+            log::info!("Resuming after {} ({} crates skipped)", name, processed);
+        } else {
+            log::warn!(
+                "Checkpoint {} is not in the index, starting from the beginning",
+                name
+            );
+            processed = 0;
+            crates = index.crates().filter(matches);
+        }
+    }
+
+    loop {
+        let chunk: Vec<_> = crates.by_ref().take(128).collect();
+        let Some(last) = chunk.last().map(|c| c.name().to_string()) else {
+            break;
+        };
+
+        let reported = processed / PROGRESS_INTERVAL;
+        processed += chunk.len();
+
         let tasks: Vec<_> = chunk
             .into_iter()
             .map(|c| do_crate(c, conn.clone()))
             .collect();
 
         futures::future::join_all(tasks).await;
+
+        if processed / PROGRESS_INTERVAL != reported {
+            log_progress(processed, total);
+        }
+
+        if let Some(path) = checkpoint {
+            std::fs::write(path, &last)?;
+        }
     }
 
+    log_progress(processed, total);
+
     Ok(())
 }
 
+/// Log the number of crates processed out of `total`, along with the percentage done.
+fn log_progress(processed: usize, total: usize) {
+    log::info!(
+        "Processed {}/{} crates ({}%)",
+        processed,
+        total,
+        processed * 100 / total.max(1)
+    );
+}
+
 ///
 /// # Panics
 /// asdf
 /// # Errors
 /// asdf
-pub async fn update_missing_crates(conn: Arc<Db>) -> Result<(), Error> {
+pub async fn update_missing_crates(conn: Arc<dyn GraphStore>) -> Result<(), Error> {
     let index = crates_index::Index::new_cargo_default()?;
 
-    let do_crate = |c: Crate, db: Arc<Db>| async move {
+    let do_crate = |c: Crate, db: Arc<dyn GraphStore>| async move {
         if let Ok(res) = db.crate_exists(c.name()).await {
             if !res {
                 println!("Missing crate: {}", c.name());
@@ -84,43 +242,179 @@ pub async fn update_missing_crates(conn: Arc<Db>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Returns the dependencies of a version from the index, in the form stored in the database.
+#[must_use]
+pub fn version_dependencies(v: &crates_index::Version) -> Vec<Dependency> {
+    v.dependencies()
+        .iter()
+        .map(|d| {
+            (
+                d.name().to_string(),
+                d.requirement().to_string(),
+                d.features().join(", "),
+                format!("{:?}", d.kind()),
+                format!("{}", d.is_optional()),
+            )
+        })
+        .collect()
+}
+
+/// Crates of the index, each parsed from its index file on first use and kept for the rest of
+/// the run. Resolving every version of the index looks up the same dependencies many times over.
+pub struct CachedIndex<'i> {
+    index: &'i crates_index::Index,
+    crates: HashMap<String, Option<Crate>>,
+}
+impl<'i> CachedIndex<'i> {
+    #[must_use]
+    pub fn new(index: &'i crates_index::Index) -> Self {
+        Self {
+            index,
+            crates: HashMap::new(),
+        }
+    }
+
+    /// Returns the crate with the given name, or `None` if it is not in the index.
+    pub fn crate_(&mut self, name: &str) -> Option<&Crate> {
+        let index = self.index;
+        self.crates
+            .entry(name.to_string())
+            .or_insert_with(|| index.crate_(name))
+            .as_ref()
+    }
+}
+
+/// Resolve a dependency requirement against the versions of a crate published in the index,
+/// picking the highest matching version in the same way cargo would for a fresh lockfile. Yanked
+/// versions and versions which are not valid semver are never selected. Returns `None` if the
+/// crate does not exist, the requirement can not be parsed, or no published version matches.
+#[must_use]
+pub fn resolve_requirement(
+    index: &mut CachedIndex,
+    name: &str,
+    requirement: &str,
+) -> Option<String> {
+    resolve_in_crate(index.crate_(name)?, requirement)
+}
+
+/// Resolve a dependency requirement against the published versions of a single crate, as
+/// `resolve_requirement` does once the crate is found in the index.
+#[must_use]
+pub fn resolve_in_crate(c: &Crate, requirement: &str) -> Option<String> {
+    let req = semver::VersionReq::parse(requirement).ok()?;
+
+    c.versions()
+        .iter()
+        .filter(|v| !v.is_yanked())
+        .filter_map(|v| {
+            semver::Version::parse(v.version())
+                .ok()
+                .filter(|semver| req.matches(semver))
+                .map(|semver| (semver, v.version().to_string()))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, version)| version)
+}
+
+/// Returns the `(name, version)` every dependency of a version resolves to with
+/// `resolve_requirement`. Dependencies which can not be resolved are left out.
+#[must_use]
+pub fn resolve_dependencies(
+    index: &mut CachedIndex,
+    v: &crates_index::Version,
+) -> Vec<(String, String)> {
+    v.dependencies()
+        .iter()
+        .filter_map(|d| {
+            let name = d.crate_name();
+            resolve_requirement(index, name, d.requirement())
+                .map(|version| (name.to_string(), version))
+        })
+        .collect()
+}
+
+/// Insert exact `(Version)-[:DEPENDS_ON]->(Version)` relationships for every version in the
+/// index, resolving each dependency requirement against the currently published versions. Crates
+/// depended on are parsed once for the whole run, see `CachedIndex`.
+///
+/// # Panics
+/// This function will panic if the lock of the `CachedIndex` has been poisoned.
+/// # Errors
+/// Returns an `painter::index::Error` if the index can not be opened. Failures of individual
+/// versions are logged and do not stop resolution of the rest.
+pub async fn insert_resolved_dependencies(conn: Arc<dyn GraphStore>) -> Result<(), Error> {
+    let index = crates_index::Index::new_cargo_default()?;
+    let cached = Mutex::new(CachedIndex::new(&index));
+    let cached = &cached;
+
+    let do_crate = |c: Crate, db: Arc<dyn GraphStore>| async move {
+        // Resolve every version up front, so the lock is never held across an await
+        let resolved: Vec<_> = {
+            let mut cached = cached.lock().unwrap();
+            c.versions()
+                .iter()
+                .map(|v| resolve_dependencies(&mut cached, v))
+                .collect()
+        };
+
+        for (v, resolved) in c.versions().iter().zip(resolved) {
+            if let Err(e) = db
+                .insert_resolved_dependencies((v.name(), v.version()), &resolved)
+                .await
+            {
+                log::error!("Failed resolving {}-{}: {}", v.name(), v.version(), e);
+            }
+        }
+    };
+
+    let iter = index.crates().array_chunks::<128>();
+    for chunk in iter {
+        let tasks: Vec<_> = chunk
+            .into_iter()
+            .map(|c| do_crate(c, conn.clone()))
+            .collect();
+
+        futures::future::join_all(tasks).await;
+    }
+
+    Ok(())
+}
+
 ///
 /// # Panics
 ///
 /// # Errors
 ///
-pub async fn insert_fresh_crate(c: Crate, db: Arc<Db>) -> Result<(), Error> {
+pub async fn insert_fresh_crate(c: Crate, db: Arc<dyn GraphStore>) -> Result<(), Error> {
     for v in c.versions() {
-        let depends: Vec<_> = v
-            .dependencies()
-            .iter()
-            .map(|d| {
-                (
-                    d.name(),
-                    d.requirement(),
-                    d.features().join(", "),
-                    format!("{:?}", d.kind()),
-                    format!("{}", d.is_optional()),
-                )
-            })
-            .collect();
-
-        db.insert_crate_version(v.name(), v.version(), depends.iter())
-            .await?;
+        db.insert_crate_version(
+            v.name(),
+            v.version(),
+            v.is_yanked(),
+            &version_dependencies(v),
+        )
+        .await?;
     }
 
     Ok(())
 }
 
+/// Create the schema and insert every crate of the index matching `filter`, with all of its
+/// versions.
 ///
 /// # Panics
 /// asdf
 /// # Errors
 /// asdf
-pub async fn create_fresh_db(conn: Arc<Db>) -> Result<(), Error> {
+pub async fn create_fresh_db(conn: Arc<dyn GraphStore>, filter: &CrateFilter) -> Result<(), Error> {
+    conn.init_schema().await?;
+
     let index = crates_index::Index::new_cargo_default()?;
 
-    let iter = index.crates().array_chunks::<12>();
+    let iter = index
+        .crates()
+        .filter(|c| filter.matches(c.name()))
+        .array_chunks::<12>();
     for chunk in iter {
         let tasks: Vec<_> = chunk
             .into_iter()
@@ -133,17 +427,44 @@ pub async fn create_fresh_db(conn: Arc<Db>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Returns the highest version of a crate which is neither yanked nor a pre-release. Versions
+/// which are not valid semver are never selected. A crate which has only published pre-releases
+/// has no stable version, so `None` is returned for it rather than its highest pre-release.
+#[must_use]
+pub fn latest_version(c: &Crate) -> Option<&crates_index::Version> {
+    c.versions()
+        .iter()
+        .filter(|v| !v.is_yanked())
+        .filter_map(|v| {
+            semver::Version::parse(v.version())
+                .ok()
+                .filter(|semver| semver.pre.is_empty())
+                .map(|semver| (semver, v))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, v)| v)
+}
+
+/// Mark the latest version of every crate in the index, as selected by `latest_version`. Crates
+/// without any stable, non-yanked version are left without a latest version.
 ///
 /// # Panics
-/// asdf
+/// This function should not panic.
 /// # Errors
-/// asdf
-pub async fn set_latest_versions(conn: Arc<Db>) -> Result<(), Error> {
+/// Returns an `painter::index::Error` if the index can not be opened. Failures of individual
+/// crates are logged and do not stop the update.
+pub async fn set_latest_versions(conn: Arc<dyn GraphStore>) -> Result<(), Error> {
     let index = crates_index::Index::new_cargo_default()?;
 
-    let do_crate = |c: Crate, db: Arc<Db>| async move {
-        let latest = c.highest_version();
-        db.set_latest(c.name(), latest.version()).await;
+    let do_crate = |c: Crate, db: Arc<dyn GraphStore>| async move {
+        let Some(latest) = latest_version(&c) else {
+            log::warn!("No stable version of {}", c.name());
+            return;
+        };
+
+        if let Err(e) = db.set_latest(c.name(), latest.version()).await {
+            log::error!("Failed setting latest of {}: {}", c.name(), e);
+        }
     };
 
     let iter = index.crates().array_chunks::<128>();
@@ -158,3 +479,100 @@ pub async fn set_latest_versions(conn: Arc<Db>) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crate_filter_matches() {
+        let names = CrateFilter::parse_names("serde\n  tokio  \n\n# comment\nserde_json\n");
+        assert_eq!(names.len(), 3);
+        assert!(names.contains("tokio"));
+
+        assert!(CrateFilter::default().matches("anything"));
+
+        let filter = CrateFilter {
+            only: Some(names),
+            skip: HashSet::from(["tokio".to_string()]),
+            prefix: Some("serde".to_string()),
+        };
+        assert!(filter.matches("serde"));
+        assert!(filter.matches("serde_json"));
+        assert!(!filter.matches("tokio"));
+        assert!(!filter.matches("serde_yaml"));
+    }
+
+    /// Build a crate from index lines of `(version, yanked)`.
+    fn index_crate(versions: &[(&str, bool)]) -> Crate {
+        let lines: Vec<_> = versions
+            .iter()
+            .map(|(version, yanked)| {
+                serde_json::json!({
+                    "name": "foo",
+                    "vers": version,
+                    "deps": [],
+                    "features": {},
+                    "cksum": "0".repeat(64),
+                    "yanked": yanked,
+                })
+                .to_string()
+            })
+            .collect();
+        Crate::from_slice(lines.join("\n").as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn resolve_requirements() {
+        let c = index_crate(&[
+            ("0.9.0", false),
+            ("1.0.0", false),
+            ("1.2.0", false),
+            ("1.2.5", false),
+            ("1.3.0", true),
+            ("2.0.0-rc.1", false),
+        ]);
+
+        // Caret, including the implicit caret of a bare version, skipping the yanked 1.3.0
+        assert_eq!(resolve_in_crate(&c, "^1.0").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve_in_crate(&c, "1.0.0").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve_in_crate(&c, "~1.2.0").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve_in_crate(&c, "~1.0").as_deref(), Some("1.0.0"));
+        // Wildcards never match pre-releases
+        assert_eq!(resolve_in_crate(&c, "*").as_deref(), Some("1.2.5"));
+        assert_eq!(resolve_in_crate(&c, "0.*").as_deref(), Some("0.9.0"));
+
+        assert_eq!(resolve_in_crate(&c, "^3"), None);
+        assert_eq!(resolve_in_crate(&c, "not a requirement"), None);
+        assert_eq!(resolve_in_crate(&c, "=1.3.0"), None);
+        assert_eq!(
+            resolve_in_crate(&index_crate(&[("1.0.0", true), ("1.1.0", true)]), "^1"),
+            None
+        );
+    }
+
+    #[test]
+    fn latest_versions() {
+        let latest = |versions: &[(&str, bool)]| {
+            latest_version(&index_crate(versions)).map(|v| v.version().to_string())
+        };
+
+        assert_eq!(
+            latest(&[("1.0.0", false), ("1.1.0", true)]).as_deref(),
+            Some("1.0.0")
+        );
+        assert_eq!(
+            latest(&[("1.0.0", false), ("1.10.0", false), ("1.9.0", false)]).as_deref(),
+            Some("1.10.0")
+        );
+        assert_eq!(
+            latest(&[("1.0.0", false), ("2.0.0-beta.1", false)]).as_deref(),
+            Some("1.0.0")
+        );
+        assert_eq!(
+            latest(&[("0.1.0-alpha", false), ("0.1.0-beta", false)]),
+            None
+        );
+        assert_eq!(latest(&[("1.0.0", true)]), None);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{
+    analysis::{demangle_symbol, load_module},
+    Error,
+};
+use llvm_ir_analysis::{
+    llvm_ir::{Function, Module},
+    ModuleAnalysis,
+};
+use std::{io::Write, path::Path};
+
+/// Analyses of a single module which can be printed by `inspect`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectTarget {
+    /// The call graph of the module, with demangled function names.
+    CallGraph,
+    /// The control flow graph of each function.
+    Cfg,
+    /// The dominator tree of each function, as edges from each block to its immediate dominator.
+    Domtree,
+}
+
+/// Returns true if `func` names the function with the given symbol, by its symbol or demangled
+/// name.
+fn is_named(symbol: &str, func: &str) -> bool {
+    symbol == func || demangle_symbol(symbol, false) == func
+}
+
+/// Find the functions to inspect, either the one named `func` by its symbol or demangled name, or
+/// every function defined in the module.
+fn select_functions<'m>(
+    module: &'m Module,
+    func: Option<&str>,
+) -> Result<Vec<&'m Function>, Error> {
+    match func {
+        None => Ok(module.functions.iter().collect()),
+        Some(name) => module
+            .functions
+            .iter()
+            .find(|f| is_named(&f.name, name))
+            .map(|f| vec![f])
+            .ok_or_else(|| Error::FunctionNotFound(name.to_string())),
+    }
+}
+
+fn write_call_graph<W: Write>(
+    out: &mut W,
+    analysis: &ModuleAnalysis,
+    func: Option<&str>,
+) -> std::io::Result<()> {
+    let graph = analysis.call_graph();
+
+    writeln!(out, "digraph call_graph {{")?;
+    for (caller, callee, _) in graph.inner().all_edges() {
+        if func.map_or(true, |f| is_named(caller, f) || is_named(callee, f)) {
+            let caller = demangle_symbol(caller, false);
+            let callee = demangle_symbol(callee, false);
+            writeln!(out, "    {caller:?} -> {callee:?};")?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+fn write_cfg<W: Write>(
+    out: &mut W,
+    analysis: &ModuleAnalysis,
+    function: &Function,
+) -> std::io::Result<()> {
+    let fn_analysis = analysis.fn_analysis(&function.name);
+    let cfg = fn_analysis.control_flow_graph();
+
+    writeln!(
+        out,
+        "digraph {:?} {{",
+        demangle_symbol(&function.name, false)
+    )?;
+    for bb in &function.basic_blocks {
+        for succ in cfg.succs(&bb.name) {
+            writeln!(
+                out,
+                "    {:?} -> {:?};",
+                bb.name.to_string(),
+                succ.to_string()
+            )?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+fn write_domtree<W: Write>(
+    out: &mut W,
+    analysis: &ModuleAnalysis,
+    function: &Function,
+) -> std::io::Result<()> {
+    let fn_analysis = analysis.fn_analysis(&function.name);
+    let domtree = fn_analysis.dominator_tree();
+
+    writeln!(
+        out,
+        "digraph {:?} {{",
+        demangle_symbol(&function.name, false)
+    )?;
+    for bb in &function.basic_blocks {
+        if let Some(idom) = domtree.idom(&bb.name) {
+            writeln!(
+                out,
+                "    {:?} -> {:?};",
+                idom.to_string(),
+                bb.name.to_string()
+            )?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// Load a single bitcode or textual IR module and write the requested analysis as DOT. The call
+/// graph is written as a single graph, limited to the calls to or from `func` if given. CFGs and
+/// dominator trees are written as one graph per function, for `func` only if given.
+///
+/// # Errors
+/// Returns `painter::Error` if the module fails to load, `func` is not a function defined in the
+/// module, or the output can not be written.
+pub fn inspect<P: AsRef<Path>, W: Write>(
+    file: P,
+    what: InspectTarget,
+    func: Option<&str>,
+    out: &mut W,
+) -> Result<(), Error> {
+    let module = load_module(file.as_ref())?;
+    let analysis = ModuleAnalysis::new(&module);
+
+    match what {
+        InspectTarget::CallGraph => {
+            // Fail on an unknown function rather than writing an empty graph
+            select_functions(&module, func)?;
+            write_call_graph(out, &analysis, func)?;
+        }
+        InspectTarget::Cfg => {
+            for function in select_functions(&module, func)? {
+                write_cfg(out, &analysis, function)?;
+            }
+        }
+        InspectTarget::Domtree => {
+            for function in select_functions(&module, func)? {
+                write_domtree(out, &analysis, function)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &str = "test_data/simple_test-128fe9b9c0bcc910.bc";
+
+    fn render(what: InspectTarget, func: Option<&str>) -> String {
+        let mut out = Vec::new();
+        inspect(MODULE, what, func, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn inspect_call_graph() {
+        let dot = render(InspectTarget::CallGraph, None);
+        assert!(dot.starts_with("digraph call_graph {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    \"simple_test::main\" -> \"simplecrypt::encrypt\";\n"));
+        assert!(dot.contains("    \"simple_test::main\" -> \"simplecrypt::decrypt\";\n"));
+
+        // Limited to a function, every edge is a call to or from it
+        let dot = render(InspectTarget::CallGraph, Some("simple_test::main"));
+        let edges: Vec<_> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert!(!edges.is_empty());
+        assert!(edges
+            .iter()
+            .all(|edge| edge.contains("\"simple_test::main\"")));
+
+        // The mangled symbol selects the same calls
+        assert_eq!(
+            render(
+                InspectTarget::CallGraph,
+                Some("_ZN11simple_test4main17h73a014efd6884b65E")
+            ),
+            dot
+        );
+        let mut out = Vec::new();
+        assert!(matches!(
+            inspect(
+                MODULE,
+                InspectTarget::CallGraph,
+                Some("no_such_fn"),
+                &mut out
+            ),
+            Err(Error::FunctionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn inspect_function_graphs() {
+        let cfg = render(InspectTarget::Cfg, Some("simple_test::main"));
+        assert!(cfg.starts_with("digraph \"simple_test::main\" {\n"));
+        assert_eq!(cfg.matches("digraph").count(), 1);
+        assert!(cfg.contains("bb6\";\n"));
+
+        let domtree = render(InspectTarget::Domtree, Some("simple_test::main"));
+        assert!(domtree.starts_with("digraph \"simple_test::main\" {\n"));
+        assert!(domtree.contains("bb6\";\n"));
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            inspect(MODULE, InspectTarget::Cfg, Some("no_such_fn"), &mut out),
+            Err(Error::FunctionNotFound(_))
+        ));
+    }
+}
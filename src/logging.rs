@@ -0,0 +1,147 @@
+use serde_json::{Map, Value};
+use std::{cell::RefCell, marker::PhantomData, time::Duration};
+
+thread_local! {
+    /// Fields of the spans entered on this thread, outermost first.
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = RefCell::new(Vec::new());
+}
+
+/// Formats of the log output.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain text lines, as formatted by `env_logger`.
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message` fields, along
+    /// with the fields of any `Span` the record was logged in.
+    Json,
+}
+
+/// Initialize logging in the given format, with levels configured by `RUST_LOG`.
+pub fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            use std::io::Write;
+
+            let mut line = Map::new();
+            line.insert("timestamp".into(), buf.timestamp().to_string().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+            // env_logger formats on the logging thread, so these are the fields of its spans
+            FIELDS.with(|fields| {
+                for (key, value) in fields.borrow().iter() {
+                    line.insert((*key).to_string(), value.clone());
+                }
+            });
+
+            writeln!(buf, "{}", Value::Object(line))
+        });
+    }
+    builder.init();
+}
+
+/// Push fields onto the spans of this thread, returning the depth to truncate back to.
+fn push_fields<const N: usize>(new: [(&'static str, Value); N]) -> usize {
+    FIELDS.with(|fields| {
+        let mut fields = fields.borrow_mut();
+        let depth = fields.len();
+        fields.extend(new);
+        depth
+    })
+}
+
+fn truncate_fields(depth: usize) {
+    FIELDS.with(|fields| fields.borrow_mut().truncate(depth));
+}
+
+/// A stage of processing a single crate version, such as compiling or exporting it. While a span
+/// is entered, every record logged on the same thread carries its `stage`, `crate` and `version`
+/// as separate fields in the JSON log format, and nested spans override the fields of outer ones.
+///
+/// Spans are tied to the thread they were entered on, so they can not be held across an `.await`.
+/// Async code should enter a span only around synchronous logging, or use `log_finished`.
+pub struct Span {
+    stage: &'static str,
+    fullname: String,
+    start: std::time::Instant,
+    depth: usize,
+    _thread: PhantomData<*const ()>,
+}
+impl Span {
+    /// Enter a span for `stage` of a crate version, until it is dropped or finished.
+    #[must_use]
+    pub fn enter(stage: &'static str, name: &str, version: &str) -> Self {
+        let depth = push_fields([
+            ("stage", stage.into()),
+            ("crate", name.into()),
+            ("version", version.into()),
+        ]);
+
+        Self {
+            stage,
+            fullname: format!("{name}-{version}"),
+            start: std::time::Instant::now(),
+            depth,
+            _thread: PhantomData,
+        }
+    }
+
+    /// Log the completion of the stage at debug level with its `duration_ms`, and exit the span.
+    pub fn finish(self) {
+        log_duration(self.stage, &self.fullname, self.start.elapsed());
+    }
+}
+impl Drop for Span {
+    fn drop(&mut self) {
+        truncate_fields(self.depth);
+    }
+}
+
+fn log_duration(stage: &str, fullname: &str, elapsed: Duration) {
+    let depth = push_fields([(
+        "duration_ms",
+        u64::try_from(elapsed.as_millis())
+            .unwrap_or(u64::MAX)
+            .into(),
+    )]);
+    log::debug!("Finished {} of {} in {:?}", stage, fullname, elapsed);
+    truncate_fields(depth);
+}
+
+/// Log the completion of a stage which took `elapsed`, as `Span::finish` does. This is for stages
+/// which await, where a `Span` can not be held while the stage runs.
+pub fn log_finished(stage: &'static str, name: &str, version: &str, elapsed: Duration) {
+    let span = Span::enter(stage, name, version);
+    log_duration(stage, &span.fullname, elapsed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_fields() -> Vec<(&'static str, Value)> {
+        FIELDS.with(|fields| fields.borrow().clone())
+    }
+
+    #[test]
+    fn span_fields() {
+        {
+            let _outer = Span::enter("compile", "serde", "1.0.152");
+            {
+                let _inner = Span::enter("extract", "serde", "1.0.152");
+                assert_eq!(current_fields().len(), 6);
+                assert_eq!(current_fields()[3], ("stage", Value::from("extract")));
+            }
+            assert_eq!(
+                current_fields(),
+                [
+                    ("stage", Value::from("compile")),
+                    ("crate", Value::from("serde")),
+                    ("version", Value::from("1.0.152")),
+                ]
+            );
+        }
+        assert!(current_fields().is_empty());
+    }
+}
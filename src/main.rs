@@ -4,18 +4,27 @@
 #![feature(iter_array_chunks)]
 mod analysis;
 mod compile;
+mod config;
 mod crate_fs;
 mod db;
 mod index;
+mod inspect;
+mod logging;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod store;
 
 use clap::{Parser, Subcommand};
+use config::Config;
 use crate_fs::{CrateFs, CrateFsConfig};
 use db::Db;
 use rayon::prelude::*;
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
+use store::GraphStore;
 
 /// Top error type returned during any stage of analysis from compile to data import.
 #[derive(thiserror::Error, Debug)]
@@ -41,17 +50,48 @@ pub enum Error {
     #[error("Indexing Error: {0}")]
     CrateFsError(#[from] crate_fs::Error),
     ///
-    #[error("MissingCompressedPath")]
-    MissingCompressedPath,
+    #[error("Config Error: {0}")]
+    ConfigError(#[from] config::Error),
     ///
-    #[error("MissingExtractedSourcesPath")]
-    MissingExtractedSourcesPath,
+    #[error("Compile Error: {0}")]
+    CompileError(#[from] compile::Error),
+    ///
+    #[error("Function not found in module: {0}")]
+    FunctionNotFound(String),
+    ///
+    #[error("Missing {0} root, pass it with {1} or set it in the [roots] section of the config")]
+    MissingRoot(&'static str, &'static str),
+    ///
+    #[error("Missing database argument: {0}")]
+    MissingDbArgument(&'static str),
+    ///
+    #[error("Required tool not found on PATH: {0}")]
+    MissingTool(&'static str),
+    ///
+    #[error("Invalid output from count-unsafe: {0}")]
+    CountUnsafeOutput(String),
+    ///
+    #[error(
+        "Cache capacity {0} is outside of {}..={}",
+        crate_fs::MIN_CACHE_CAPACITY,
+        crate_fs::MAX_CACHE_CAPACITY
+    )]
+    InvalidCacheCapacity(usize),
+    ///
+    #[error("Failed loading {1} bytecode modules of {0}")]
+    IncompleteBytecode(PathBuf, usize),
 }
 
 /// Top level arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Format of the log output. Log levels are configured with `RUST_LOG`.
+    #[arg(long, global = true, value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+    /// Read settings from a TOML config file. Flags given on the command line take precedence.
+    #[arg(long, global = true, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    config: Option<PathBuf>,
     /// The command stage to execute.
     #[command(subcommand)]
     command: Command,
@@ -67,14 +107,205 @@ struct Args {
 struct Roots {
     /// Root directory containing the extracted sources tree.
     #[arg(short = 's', value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
-    pub sources_root: PathBuf,
+    pub sources_root: Option<PathBuf>,
     /// Root directory containing bytecodes, matching the name-version layout of the source tree.
     /// Can be the same root path to output bytecode artifacts into the source tree.
     #[arg(short = 'b', value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
     pub bytecodes_root: Option<PathBuf>,
 
     #[arg(short = 'c', value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
-    pub compressed_root: PathBuf,
+    pub compressed_root: Option<PathBuf>,
+
+    /// Maximum number of extracted crates kept on disk at once, between 128 and 1024.
+    #[arg(long, value_name = "COUNT")]
+    pub cache_capacity: Option<usize>,
+}
+impl Roots {
+    /// Fill in any roots not given on the command line from the config file.
+    fn merge(&mut self, config: &Config) {
+        merge_option(&mut self.sources_root, config.roots.sources.as_ref());
+        merge_option(&mut self.bytecodes_root, config.roots.bytecodes.as_ref());
+        merge_option(&mut self.compressed_root, config.roots.compressed.as_ref());
+        merge_option(&mut self.cache_capacity, config.cache_capacity.as_ref());
+    }
+
+    /// The extracted sources root, given with `-s` or in the config file.
+    fn sources(&self) -> Result<&Path, Error> {
+        required_root(self.sources_root.as_ref(), "sources", "-s")
+    }
+
+    /// The bytecodes root, given with `-b` or in the config file.
+    fn bytecodes(&self) -> Result<&Path, Error> {
+        required_root(self.bytecodes_root.as_ref(), "bytecodes", "-b")
+    }
+
+    /// The compressed `.crate` files root, given with `-c` or in the config file.
+    fn compressed(&self) -> Result<&Path, Error> {
+        required_root(self.compressed_root.as_ref(), "compressed", "-c")
+    }
+}
+
+/// Returns a root which is required by the command, or `Error::MissingRoot` naming the flag it is
+/// given with if it is set neither on the command line nor in the config file.
+fn required_root<'a>(
+    root: Option<&'a PathBuf>,
+    name: &'static str,
+    flag: &'static str,
+) -> Result<&'a Path, Error> {
+    root.map(PathBuf::as_path)
+        .ok_or(Error::MissingRoot(name, flag))
+}
+
+/// Use the config file value of a setting if it was not given on the command line.
+fn merge_option<T: Clone>(arg: &mut Option<T>, config: Option<&T>) {
+    if arg.is_none() {
+        *arg = config.cloned();
+    }
+}
+
+/// Storage backends the crate graph can be written to.
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A running Neo4j instance, specified by host and credentials.
+    Neo4j,
+    /// A local SQLite database file.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// Clap argument object for limiting a run to a subset of the crates in the index.
+#[derive(clap::Args, Debug, Clone)]
+struct FilterArgs {
+    /// Only process the crates listed in this file, one name per line.
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub only: Option<PathBuf>,
+    /// Never process the crates listed in this file, one name per line.
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub skip: Option<PathBuf>,
+    /// Only process crates whose name starts with this prefix.
+    #[arg(long, value_name = "PREFIX")]
+    pub name_prefix: Option<String>,
+}
+impl FilterArgs {
+    /// Read the crate lists into an `index::CrateFilter`.
+    fn load(&self) -> Result<index::CrateFilter, Error> {
+        Ok(index::CrateFilter::new(
+            self.only.as_deref(),
+            self.skip.as_deref(),
+            self.name_prefix.clone(),
+        )?)
+    }
+}
+
+/// Environment variable the Neo4j password is read from when it is not given with `-p`.
+const PASSWORD_ENV: &str = "NEO4J_PASSWORD";
+
+/// Clap argument object for specifying the database backend and its connection parameters.
+#[derive(clap::Args, Debug, Clone)]
+struct DbArgs {
+    /// The storage backend to use. Defaults to neo4j.
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+    /// Neo4j host URI.
+    #[arg(short = 'd')]
+    pub host: Option<String>,
+    /// Neo4j username.
+    #[arg(short = 'u')]
+    pub username: Option<String>,
+    /// Neo4j password. Prefer `--password-stdin` or the `NEO4J_PASSWORD` environment variable,
+    /// which do not expose the password in shell history and process lists.
+    #[arg(short = 'p')]
+    pub password: Option<String>,
+    /// Read the Neo4j password from the first line of stdin.
+    #[arg(long, conflicts_with = "password")]
+    pub password_stdin: bool,
+    /// Name of the Neo4j database within the instance. Defaults to neo4j.
+    #[arg(long, value_name = "NAME")]
+    pub database: Option<String>,
+    /// Number of rows fetched per round trip from Neo4j.
+    #[arg(long, default_value_t = db::DEFAULT_FETCH_SIZE)]
+    pub fetch_size: usize,
+    /// Maximum number of pooled Neo4j connections.
+    #[arg(long, default_value_t = db::DEFAULT_MAX_CONNECTIONS)]
+    pub max_connections: usize,
+    /// Path of the database file for embedded backends.
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub path: Option<PathBuf>,
+}
+impl DbArgs {
+    /// Fill in any connection parameters not given on the command line from the config file.
+    fn merge(&mut self, config: &Config) {
+        merge_option(&mut self.backend, config.db.backend.as_ref());
+        merge_option(&mut self.host, config.db.host.as_ref());
+        merge_option(&mut self.username, config.db.username.as_ref());
+        merge_option(&mut self.database, config.db.database.as_ref());
+        if !self.password_stdin && std::env::var_os(PASSWORD_ENV).is_none() {
+            merge_option(&mut self.password, config.db.password.as_ref());
+        }
+        merge_option(&mut self.path, config.db.path.as_ref());
+    }
+
+    /// Resolve the Neo4j password from `-p`, stdin if `--password-stdin` is set, then
+    /// `NEO4J_PASSWORD`.
+    fn password(&self) -> Result<String, Error> {
+        use std::io::{BufRead, IsTerminal, Write};
+
+        if let Some(password) = &self.password {
+            return Ok(password.clone());
+        }
+
+        if self.password_stdin {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                eprint!("Neo4j password: ");
+                std::io::stderr().flush()?;
+            }
+
+            let mut password = String::new();
+            stdin.lock().read_line(&mut password)?;
+            return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+        }
+
+        std::env::var(PASSWORD_ENV)
+            .map_err(|_| Error::MissingDbArgument("-p, --password-stdin or NEO4J_PASSWORD"))
+    }
+
+    /// Connect to the configured backend.
+    async fn connect(&self) -> Result<Arc<dyn GraphStore>, Error> {
+        match self.backend.unwrap_or(Backend::Neo4j) {
+            Backend::Neo4j => {
+                let host = self.host.as_ref().ok_or(Error::MissingDbArgument("-d"))?;
+                let username = self
+                    .username
+                    .as_ref()
+                    .ok_or(Error::MissingDbArgument("-u"))?;
+                let password = self.password()?;
+
+                let mut config = db::DbConfig {
+                    fetch_size: self.fetch_size,
+                    max_connections: self.max_connections,
+                    ..db::DbConfig::default()
+                };
+                if let Some(database) = &self.database {
+                    config.database.clone_from(database);
+                }
+
+                Ok(Arc::new(
+                    Db::connect(host, username, &password, config).await?,
+                ))
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => {
+                let path = self
+                    .path
+                    .as_ref()
+                    .ok_or(Error::MissingDbArgument("--path"))?;
+
+                Ok(Arc::new(sqlite::SqliteStore::open(path)?))
+            }
+        }
+    }
 }
 
 /// Command stages of painter to execute.
@@ -87,146 +318,414 @@ enum Command {
         crate_fullname: String,
         #[command(flatten)]
         roots: Roots,
+        /// The toolchain to compile the crate with, as passed to `cargo +<TOOLCHAIN>`.
+        #[arg(long)]
+        toolchain: Option<String>,
+        /// How the crate is compiled to bytecode.
+        #[arg(long, value_enum, default_value_t = compile::CodegenMode::NoLto)]
+        codegen_mode: compile::CodegenMode,
     },
     /// Compile all crates found within the source tree.
     CompileAll {
         #[command(flatten)]
         roots: Roots,
+        /// Compile crates again even if they already have bytecode in the bytecode root, which are
+        /// skipped by default.
+        #[arg(long)]
+        rebuild: bool,
+        /// Attempt crates which failed to compile in a previous run.
+        #[arg(long)]
+        retry_failures: bool,
+        /// The toolchain to compile crates with, as passed to `cargo +<TOOLCHAIN>`.
+        #[arg(long)]
+        toolchain: Option<String>,
+        /// How crates are compiled to bytecode.
+        #[arg(long, value_enum, default_value_t = compile::CodegenMode::NoLto)]
+        codegen_mode: compile::CodegenMode,
+        /// Print how many crates would be compiled, without compiling anything.
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
     },
     /// Export all crates with built bytecode to the neo4j database
     ExportAllNeo4j {
-        #[arg(short = 'd')]
-        host: String,
-        #[arg(short = 'u')]
-        username: String,
-        #[arg(short = 'p')]
-        password: String,
+        #[command(flatten)]
+        db: DbArgs,
         #[command(flatten)]
         roots: Roots,
+        /// Exclude calls where either function name contains this string, or with
+        /// `class:<intrinsic|std|core|alloc|compiler-rt|user>` is of that class. Can be repeated,
+        /// and replaces the default exclusion of `llvm.`, `__rust`, `rt::`, `std::`, `core::` and
+        /// `alloc::`.
+        #[arg(long = "exclude-pattern", value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Re-export crates which already have invocations stored, replacing them.
+        #[arg(long)]
+        force: bool,
+        /// Print how many crates would be exported, without loading bytecode or writing to the
+        /// database.
+        #[arg(long)]
+        dry_run: bool,
+        /// Number of parsed crates buffered while waiting to be written. Parsing pauses while
+        /// the queue is full. Defaults to 32.
+        #[arg(long, value_name = "CRATES")]
+        queue_depth: Option<usize>,
+        /// Number of crates written to the database concurrently. Defaults to 4.
+        #[arg(long, value_name = "COUNT")]
+        writers: Option<usize>,
+    },
+    /// Export a single crate with built bytecode to the database, replacing any invocations
+    /// already stored for it.
+    ReimportCrate {
+        /// The full name and version of the crate to export. Must match folder name in bytecode tree.
+        #[arg(short = 'c')]
+        crate_fullname: String,
+        #[command(flatten)]
+        db: DbArgs,
+        #[command(flatten)]
+        roots: Roots,
+        /// Exclude calls where either function name contains this string, or with
+        /// `class:<intrinsic|std|core|alloc|compiler-rt|user>` is of that class. Can be repeated,
+        /// and replaces the default exclusion of `llvm.`, `__rust`, `rt::`, `std::`, `core::` and
+        /// `alloc::`.
+        #[arg(long = "exclude-pattern", value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// Export the call graph of all crates with built bytecode to a JSON Lines file.
+    ExportJson {
+        #[command(flatten)]
+        roots: Roots,
+        /// The file to write the call graphs to, one crate per line.
+        #[arg(short = 'o', value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        out: PathBuf,
+        /// Exclude calls where either function name contains this string, or with
+        /// `class:<intrinsic|std|core|alloc|compiler-rt|user>` is of that class. Can be repeated,
+        /// and replaces the default exclusion of `llvm.`, `__rust`, `rt::`, `std::`, `core::` and
+        /// `alloc::`.
+        #[arg(long = "exclude-pattern", value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// Write call graph statistics of all crates with built bytecode to a CSV file.
+    Stats {
+        #[command(flatten)]
+        roots: Roots,
+        /// The CSV file to write, one row per crate.
+        #[arg(short = 'o', value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        out: PathBuf,
+    },
+    /// Store per-function metrics of all crates with built bytecode in the database, linked to
+    /// the version defining them.
+    ExportFunctions {
+        #[command(flatten)]
+        db: DbArgs,
+        #[command(flatten)]
+        roots: Roots,
+    },
+    /// Print an analysis of a single bitcode or textual IR file as DOT, without requiring the
+    /// name-version layout of the bytecode root.
+    Inspect {
+        /// The `.bc` or `.ll` file to load.
+        #[arg(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        file: PathBuf,
+        /// The analysis to print.
+        #[arg(long, value_enum, default_value_t = inspect::InspectTarget::CallGraph)]
+        what: inspect::InspectTarget,
+        /// Limit the output to a single function, by its symbol or demangled name.
+        #[arg(long, value_name = "NAME")]
+        func: Option<String>,
     },
     SemverCheck,
 
     // Database operations
     CreateFreshDb {
-        #[arg(short = 'd')]
-        host: String,
-        #[arg(short = 'u')]
-        username: String,
-        #[arg(short = 'p')]
-        password: String,
+        #[command(flatten)]
+        db: DbArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
     },
     // Database operations
     UpdateDb {
-        #[arg(short = 'd')]
-        host: String,
-        #[arg(short = 'u')]
-        username: String,
-        #[arg(short = 'p')]
-        password: String,
+        #[command(flatten)]
+        db: DbArgs,
+        /// File recording the last processed crate, used to resume an interrupted update.
+        #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        checkpoint: Option<PathBuf>,
+        #[command(flatten)]
+        filter: FilterArgs,
     },
     // Database operations
     SetLatestVersions {
-        #[arg(short = 'd')]
-        host: String,
-        #[arg(short = 'u')]
-        username: String,
-        #[arg(short = 'p')]
-        password: String,
+        #[command(flatten)]
+        db: DbArgs,
+    },
+    /// Resolve the dependency requirements of every version against the published versions in
+    /// the index, storing exact version to version dependencies.
+    ResolveDependencies {
+        #[command(flatten)]
+        db: DbArgs,
     },
     CountUnsafe {
         #[command(flatten)]
         roots: Roots,
-        #[arg(short = 'd')]
-        host: String,
-        #[arg(short = 'u')]
-        username: String,
-        #[arg(short = 'p')]
-        password: String,
+        /// Insert versions missing from the database from the index, rather than failing to
+        /// store their counts.
+        #[arg(long)]
+        create_missing: bool,
+        /// Count unsafe usage heuristically from the built bytecode, rather than from the sources
+        /// with the `count-unsafe` tool.
+        #[arg(long)]
+        from_bytecode: bool,
+        #[command(flatten)]
+        db: DbArgs,
     },
 }
 
-/// Container object for storing the information of a given crate.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct CrateSource {
-    /// The crates name
-    name: String,
-    /// The crates semver version in `String` format.
-    version: String,
-    /// The fully qualified path the crate source was discovered and is located.
-    path: PathBuf,
+impl Command {
+    /// Fill in any settings not given on the command line from the config file.
+    fn merge(&mut self, config: &Config) {
+        match self {
+            Command::Stats { roots, .. } => roots.merge(config),
+            Command::Compile {
+                roots, toolchain, ..
+            }
+            | Command::CompileAll {
+                roots, toolchain, ..
+            } => {
+                roots.merge(config);
+                merge_option(toolchain, config.toolchain.as_ref());
+            }
+            Command::ExportAllNeo4j {
+                db,
+                roots,
+                exclude,
+                queue_depth,
+                writers,
+                ..
+            } => {
+                db.merge(config);
+                roots.merge(config);
+                merge_excludes(exclude, config);
+                merge_option(queue_depth, config.export.queue_depth.as_ref());
+                merge_option(writers, config.export.writers.as_ref());
+            }
+            Command::ReimportCrate {
+                db, roots, exclude, ..
+            } => {
+                db.merge(config);
+                roots.merge(config);
+                merge_excludes(exclude, config);
+            }
+            Command::ExportJson { roots, exclude, .. } => {
+                roots.merge(config);
+                merge_excludes(exclude, config);
+            }
+            Command::CountUnsafe { roots, db, .. } | Command::ExportFunctions { db, roots } => {
+                roots.merge(config);
+                db.merge(config);
+            }
+            Command::CreateFreshDb { db, .. }
+            | Command::UpdateDb { db, .. }
+            | Command::SetLatestVersions { db }
+            | Command::ResolveDependencies { db } => db.merge(config),
+            Command::Inspect { .. } | Command::SemverCheck => {}
+        }
+    }
+}
+
+/// Use the exclusions of the config file if none were given on the command line.
+fn merge_excludes(exclude: &mut Vec<String>, config: &Config) {
+    if exclude.is_empty() {
+        exclude.clone_from(&config.exclude_patterns);
+    }
+}
+
+/// Format a size in bytes as MiB for display.
+fn mib(bytes: u64) -> String {
+    format!("{} MiB", bytes / (1024 * 1024))
 }
 
 fn cratefs_from_roots(roots: &Roots) -> Result<CrateFs, Error> {
     // Queue up the caching FS
-    Ok(CrateFs::new(CrateFsConfig::with_paths(
-        roots.compressed_root.clone(),
-        roots.sources_root.clone(),
-    ))?)
+    let mut config = CrateFsConfig::with_paths(roots.compressed()?, roots.sources()?);
+    if let Some(capacity) = roots.cache_capacity {
+        if !(crate_fs::MIN_CACHE_CAPACITY..=crate_fs::MAX_CACHE_CAPACITY).contains(&capacity) {
+            return Err(Error::InvalidCacheCapacity(capacity));
+        }
+        config = config.with_capacity(capacity);
+    }
+
+    Ok(CrateFs::new(config)?)
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 32)]
 async fn main() -> Result<(), Error> {
-    env_logger::init();
+    let mut args = Args::parse();
+    logging::init_logging(args.log_format);
 
-    let args = Args::parse();
+    if let Some(path) = &args.config {
+        args.command.merge(&Config::load(path)?);
+    }
     log::trace!("{:?}", args);
 
     match args.command {
-        Command::CreateFreshDb {
-            host,
-            username,
-            password,
-        } => {
-            let db = Arc::new(Db::connect(host, username, password).await?);
-            index::create_fresh_db(db).await?;
+        Command::CreateFreshDb { db, filter } => {
+            let filter = filter.load()?;
+            let db = db.connect().await?;
+            index::create_fresh_db(db, &filter).await?;
         }
         Command::UpdateDb {
-            host,
-            username,
-            password,
+            db,
+            checkpoint,
+            filter,
         } => {
-            let db = Arc::new(Db::connect(host, username, password).await?);
+            let filter = filter.load()?;
+            let db = db.connect().await?;
             //index::update_missing_crates(db.clone()).await?;
-            index::update_missing_versions(db.clone()).await?;
+            index::update_missing_versions(db.clone(), checkpoint.as_deref(), &filter).await?;
         }
-        Command::SetLatestVersions {
-            host,
-            username,
-            password,
-        } => {
-            let db = Arc::new(Db::connect(host, username, password).await?);
+        Command::SetLatestVersions { db } => {
+            let db = db.connect().await?;
             //index::update_missing_crates(db.clone()).await?;
             index::set_latest_versions(db.clone()).await?;
         }
+        Command::ResolveDependencies { db } => {
+            let db = db.connect().await?;
+            index::insert_resolved_dependencies(db).await?;
+        }
         Command::Compile {
             crate_fullname,
             roots,
+            toolchain,
+            codegen_mode,
         } => {
-            // let sources = roots.get_crate_sources()?;
-            //compile_crate(&sources[&crate_fullname], roots.bytecodes_root.unwrap())?;
+            let sources = crate_fs::get_crate_sources(roots.sources()?)?;
+            let source = sources
+                .get(&crate_fullname)
+                .ok_or(crate_fs::Error::CrateNotFound)?;
+
+            compile::compile_crate(
+                &source.name,
+                &source.version,
+                &source.path,
+                roots.bytecodes()?,
+                toolchain.as_deref().unwrap_or(compile::DEFAULT_TOOLCHAIN),
+                codegen_mode,
+            )?;
         }
-        Command::CompileAll { roots } => {
-            compile::compile_all(cratefs_from_roots(&roots)?, roots.bytecodes_root.unwrap())
-                .await
-                .unwrap();
+        Command::CompileAll {
+            roots,
+            rebuild,
+            retry_failures,
+            toolchain,
+            codegen_mode,
+            dry_run,
+            filter,
+        } => {
+            let filter = filter.load()?;
+            if dry_run {
+                let plan = compile::plan_compile_all(
+                    roots.bytecodes()?,
+                    rebuild,
+                    retry_failures,
+                    &filter,
+                )?;
+                println!("total crates: {}", plan.total);
+                println!("already built: {}", plan.built);
+                println!("known failures: {}", plan.known_failures);
+                println!("to compile: {}", plan.to_compile);
+                println!("existing bytecode: {}", mib(plan.bytecode_bytes));
+                if let Some(bytes) = plan.estimated_bytes() {
+                    println!("estimated new bytecode: {}", mib(bytes));
+                }
+                return Ok(());
+            }
+
+            let summary = compile::compile_all(
+                cratefs_from_roots(&roots)?,
+                roots.bytecodes()?,
+                rebuild,
+                retry_failures,
+                toolchain.as_deref().unwrap_or(compile::DEFAULT_TOOLCHAIN),
+                codegen_mode,
+                &filter,
+            )
+            .await?;
+            log::info!(
+                "Built {} crates, skipped {}, failed {}, skipped {} known failures",
+                summary.built,
+                summary.skipped,
+                summary.failed,
+                summary.known_failures
+            );
         }
         Command::CountUnsafe {
             roots,
-            host,
-            username,
-            password,
+            create_missing,
+            from_bytecode,
+            db,
         } => {
-            let db = Arc::new(Db::connect(host, username, password).await?);
-            analysis::count_unsafe(&roots, db).await?;
+            let db = db.connect().await?;
+            let failures = if from_bytecode {
+                analysis::count_unsafe_from_bytecode(roots.bytecodes()?, db, create_missing).await?
+            } else {
+                analysis::count_unsafe(cratefs_from_roots(&roots)?, db, create_missing).await?
+            };
+            if failures > 0 {
+                log::error!("Failed storing unsafe counts of {} versions", failures);
+            }
         }
         Command::ExportAllNeo4j {
-            host,
-            username,
-            password,
+            db,
+            roots,
+            exclude,
+            force,
+            dry_run,
+            queue_depth,
+            writers,
+        } => {
+            let db = db.connect().await?;
+            if dry_run {
+                let plan = analysis::plan_export_all_db(roots.bytecodes()?, db, force).await?;
+                println!("total crates: {}", plan.total);
+                println!("already exported: {}", plan.exported);
+                println!("to export: {}", plan.to_export);
+                println!("bytecode to load: {}", mib(plan.bytecode_bytes));
+                return Ok(());
+            }
+
+            let queue = analysis::ExportQueueConfig {
+                depth: queue_depth.unwrap_or(analysis::DEFAULT_QUEUE_DEPTH),
+                writers: writers.unwrap_or(analysis::DEFAULT_WRITERS),
+            };
+            analysis::export_all_db(roots.bytecodes()?, db, &exclude, force, queue).await?;
+        }
+        Command::ReimportCrate {
+            crate_fullname,
+            db,
             roots,
+            exclude,
         } => {
-            let db = Arc::new(Db::connect(host, username, password).await?);
-            analysis::export_all_db(&roots.bytecodes_root.unwrap(), db).await?;
+            let db = db.connect().await?;
+            analysis::reimport_crate_db(roots.bytecodes()?.join(crate_fullname), db, &exclude)
+                .await?;
+        }
+        Command::ExportJson {
+            roots,
+            out,
+            exclude,
+        } => {
+            analysis::export_all_json(roots.bytecodes()?, out, &exclude)?;
+        }
+        Command::Stats { roots, out } => {
+            stats::export_stats_csv(roots.bytecodes()?, out)?;
+        }
+        Command::ExportFunctions { db, roots } => {
+            let db = db.connect().await?;
+            stats::export_functions_db(roots.bytecodes()?, db).await?;
+        }
+        Command::Inspect { file, what, func } => {
+            inspect::inspect(file, what, func.as_deref(), &mut std::io::stdout().lock())?;
         }
         Command::SemverCheck => {
             let index = crates_index::Index::new_cargo_default().unwrap();
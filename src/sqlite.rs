@@ -0,0 +1,611 @@
+use crate::{
+    analysis::{CallRecord, CountUnsafeResult},
+    db::Error,
+    stats::FunctionRecord,
+    store::{Dependency, GraphStore},
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS crates (
+    name TEXT PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS versions (
+    name TEXT NOT NULL REFERENCES crates(name),
+    version TEXT NOT NULL,
+    semver_major INTEGER,
+    semver_minor INTEGER,
+    semver_patch INTEGER,
+    semver_build TEXT,
+    semver_pre TEXT,
+    latest INTEGER NOT NULL DEFAULT 0,
+    yanked INTEGER NOT NULL DEFAULT 0,
+    unsafe_total INTEGER,
+    unsafe_functions INTEGER,
+    unsafe_exprs INTEGER,
+    unsafe_impls INTEGER,
+    unsafe_traits INTEGER,
+    unsafe_methods INTEGER,
+    safe_functions INTEGER,
+    safe_exprs INTEGER,
+    safe_impls INTEGER,
+    safe_traits INTEGER,
+    safe_methods INTEGER,
+    PRIMARY KEY (name, version)
+);
+CREATE TABLE IF NOT EXISTS depends_on (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    depend TEXT NOT NULL REFERENCES crates(name),
+    requirement TEXT NOT NULL,
+    features TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    optional INTEGER NOT NULL,
+    FOREIGN KEY (name, version) REFERENCES versions(name, version)
+);
+CREATE TABLE IF NOT EXISTS resolved_depends_on (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    depend TEXT NOT NULL,
+    depend_version TEXT NOT NULL,
+    PRIMARY KEY (name, version, depend, depend_version),
+    FOREIGN KEY (name, version) REFERENCES versions(name, version),
+    FOREIGN KEY (depend, depend_version) REFERENCES versions(name, version)
+);
+CREATE TABLE IF NOT EXISTS invokes (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    dst_crate TEXT NOT NULL,
+    caller TEXT NOT NULL,
+    callee TEXT NOT NULL,
+    caller_symbol TEXT NOT NULL,
+    callee_symbol TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    FOREIGN KEY (name, version) REFERENCES versions(name, version)
+);
+CREATE TABLE IF NOT EXISTS functions (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    function TEXT NOT NULL,
+    blocks INTEGER NOT NULL,
+    cyclomatic_complexity INTEGER NOT NULL,
+    recursive INTEGER NOT NULL,
+    call_depth INTEGER NOT NULL,
+    PRIMARY KEY (name, version, symbol),
+    FOREIGN KEY (name, version) REFERENCES versions(name, version)
+);
+CREATE INDEX IF NOT EXISTS idx_is_latest ON versions(latest);
+CREATE INDEX IF NOT EXISTS idx_depends_on_version ON depends_on(name, version);
+CREATE INDEX IF NOT EXISTS idx_depends_on_depend ON depends_on(depend);
+CREATE INDEX IF NOT EXISTS idx_invokes_version ON invokes(name, version);
+CREATE INDEX IF NOT EXISTS idx_invokes_dst_crate ON invokes(dst_crate);
+CREATE INDEX IF NOT EXISTS idx_functions_function ON functions(function);
+";
+
+fn insert_invoke(
+    conn: &Connection,
+    call: &CallRecord,
+    src_crate: (&str, &str),
+    dst_crate: &str,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO invokes (name, version, dst_crate, caller, callee, caller_symbol, callee_symbol, kind) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            src_crate.0,
+            src_crate.1,
+            dst_crate,
+            call.caller_demangled,
+            call.callee_demangled,
+            call.caller,
+            call.callee,
+            call.kind.as_str(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn insert_function(
+    conn: &Connection,
+    src_crate: (&str, &str),
+    function: &FunctionRecord,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO functions \
+         (name, version, symbol, function, blocks, cyclomatic_complexity, recursive, call_depth) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            src_crate.0,
+            src_crate.1,
+            function.symbol,
+            function.name,
+            function.metrics.blocks,
+            function.metrics.cyclomatic_complexity,
+            function.metrics.recursive,
+            function.metrics.call_depth,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Embedded SQLite implementation of `GraphStore`. Nodes and relationships of the Neo4j
+/// representation are mapped to tables:
+///
+/// - `(Crate)` -> `crates`
+/// - `(Version)` and `[:VERSION_OF]` -> `versions`
+/// - `(Version)-[:DEPENDS_ON]->(Crate)` -> `depends_on`
+/// - `(Version)-[:DEPENDS_ON]->(Version)` -> `resolved_depends_on`
+/// - `[:INVOKES]` -> `invokes`
+/// - `(Version)-[:DEFINES]->(Function)` -> `functions`
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+impl SqliteStore {
+    /// Open, or create, the SQLite database at the given path and ensure the schema exists.
+    /// Foreign keys are enforced, so rows can only refer to crates and versions which exist.
+    /// # Panics
+    /// This function will panic if the connection lock has been poisoned.
+    /// # Errors
+    /// This function will return an `painter::db::Error` if the database cannot be opened.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` with the connection on the blocking thread pool, so SQLite I/O and waiting on the
+    /// connection lock never block the async runtime.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+    }
+
+    async fn exists(&self, sql: &'static str, params: Vec<String>) -> Result<bool, Error> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(sql, rusqlite::params_from_iter(params), |_| Ok(()))
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl GraphStore for SqliteStore {
+    async fn init_schema(&self) -> Result<(), Error> {
+        self.with_conn(|conn| Ok(conn.execute_batch(SCHEMA)?)).await
+    }
+
+    async fn insert_invoke(
+        &self,
+        call: &CallRecord,
+        src_crate: (&str, &str),
+        dst_crate: &str,
+    ) -> Result<(), Error> {
+        let call = call.clone();
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+        let dst_crate = dst_crate.to_string();
+
+        self.with_conn(move |conn| {
+            insert_invoke(conn, &call, (name.as_str(), version.as_str()), &dst_crate)
+        })
+        .await
+    }
+
+    async fn insert_invokes(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        let calls = calls.to_vec();
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            for call in &calls {
+                insert_invoke(
+                    &tx,
+                    call,
+                    (name.as_str(), version.as_str()),
+                    call.callee_crate(),
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn clear_invokes(&self, name: &str, version: &str) -> Result<(), Error> {
+        let (name, version) = (name.to_string(), version.to_string());
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM invokes WHERE name = ?1 AND version = ?2",
+                [name, version],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reimport_crate(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error> {
+        let calls = calls.to_vec();
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "DELETE FROM invokes WHERE name = ?1 AND version = ?2",
+                [&name, &version],
+            )?;
+            for call in &calls {
+                insert_invoke(
+                    &tx,
+                    call,
+                    (name.as_str(), version.as_str()),
+                    call.callee_crate(),
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_crate_version(
+        &self,
+        name: &str,
+        version: &str,
+        yanked: bool,
+        depends_on: &[Dependency],
+    ) -> Result<(), Error> {
+        let semver = crate::db::parse_crate_semver(version).ok();
+        let (name, version) = (name.to_string(), version.to_string());
+        let depends_on = depends_on.to_vec();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute("INSERT OR IGNORE INTO crates (name) VALUES (?1)", [&name])?;
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO versions (name, version, semver_major, semver_minor, semver_patch, semver_build, semver_pre, yanked) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    name,
+                    version,
+                    semver.as_ref().map(|s| s.major),
+                    semver.as_ref().map(|s| s.minor),
+                    semver.as_ref().map(|s| s.patch),
+                    semver.as_ref().map(|s| s.build.to_string()),
+                    semver.as_ref().map(|s| s.pre.to_string()),
+                    yanked,
+                ],
+            )?;
+
+            // The version already exists along with its dependencies, only refresh whether it is yanked.
+            if inserted == 0 {
+                tx.execute(
+                    "UPDATE versions SET yanked = ?3 WHERE name = ?1 AND version = ?2",
+                    params![name, version, yanked],
+                )?;
+                tx.commit()?;
+
+                return Ok(());
+            }
+
+            for (depend, req, features, kind, optional) in &depends_on {
+                tx.execute("INSERT OR IGNORE INTO crates (name) VALUES (?1)", [depend])?;
+                tx.execute(
+                    "INSERT INTO depends_on (name, version, depend, requirement, features, kind, optional) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![name, version, depend, req, features, kind, optional == "true"],
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_resolved_dependencies(
+        &self,
+        src_crate: (&str, &str),
+        resolved: &[(String, String)],
+    ) -> Result<(), Error> {
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+        let resolved = resolved.to_vec();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            for (depend, depend_version) in &resolved {
+                tx.execute(
+                    "INSERT OR IGNORE INTO resolved_depends_on (name, version, depend, depend_version) \
+                     SELECT ?1, ?2, ?3, ?4 \
+                     WHERE EXISTS (SELECT 1 FROM versions WHERE name = ?3 AND version = ?4)",
+                    params![name, version, depend, depend_version],
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn crate_exists(&self, name: &str) -> Result<bool, Error> {
+        self.exists(
+            "SELECT 1 FROM crates WHERE name = ?1 LIMIT 1",
+            vec![name.to_string()],
+        )
+        .await
+    }
+
+    async fn version_exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.exists(
+            "SELECT 1 FROM versions WHERE name = ?1 AND version = ?2 LIMIT 1",
+            vec![name.to_string(), version.to_string()],
+        )
+        .await
+    }
+
+    async fn has_any_invoke(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.exists(
+            "SELECT 1 FROM invokes WHERE name = ?1 AND version = ?2 LIMIT 1",
+            vec![name.to_string(), version.to_string()],
+        )
+        .await
+    }
+
+    async fn set_latest(&self, name: &str, version: &str) -> Result<(), Error> {
+        let (name, version) = (name.to_string(), version.to_string());
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute("UPDATE versions SET latest = 0 WHERE name = ?1", [&name])?;
+            tx.execute(
+                "UPDATE versions SET latest = 1 WHERE name = ?1 AND version = ?2",
+                [&name, &version],
+            )?;
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_function(
+        &self,
+        src_crate: (&str, &str),
+        function: &FunctionRecord,
+    ) -> Result<(), Error> {
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+        let function = function.clone();
+
+        self.with_conn(move |conn| {
+            insert_function(conn, (name.as_str(), version.as_str()), &function)
+        })
+        .await
+    }
+
+    async fn insert_functions(
+        &self,
+        src_crate: (&str, &str),
+        functions: &[FunctionRecord],
+    ) -> Result<(), Error> {
+        let (name, version) = (src_crate.0.to_string(), src_crate.1.to_string());
+        let functions = functions.to_vec();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            for function in &functions {
+                insert_function(&tx, (name.as_str(), version.as_str()), function)?;
+            }
+
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_unsafe(
+        &self,
+        name: &str,
+        version: &str,
+        unsafe_result: &CountUnsafeResult,
+    ) -> Result<(), Error> {
+        let (name, version) = (name.to_string(), version.to_string());
+        let unsafe_result = unsafe_result.clone();
+
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE versions SET \
+                    unsafe_total = ?3, \
+                    unsafe_functions = ?4, \
+                    unsafe_exprs = ?5, \
+                    unsafe_impls = ?6, \
+                    unsafe_traits = ?7, \
+                    unsafe_methods = ?8, \
+                    safe_functions = ?9, \
+                    safe_exprs = ?10, \
+                    safe_impls = ?11, \
+                    safe_traits = ?12, \
+                    safe_methods = ?13 \
+                 WHERE name = ?1 AND version = ?2",
+                params![
+                    name,
+                    version,
+                    unsafe_result.total_unsafe(),
+                    unsafe_result.functions.unsafe_,
+                    unsafe_result.exprs.unsafe_,
+                    unsafe_result.item_impls.unsafe_,
+                    unsafe_result.item_traits.unsafe_,
+                    unsafe_result.methods.unsafe_,
+                    unsafe_result.functions.safe,
+                    unsafe_result.exprs.safe,
+                    unsafe_result.item_impls.safe,
+                    unsafe_result.item_traits.safe,
+                    unsafe_result.methods.safe,
+                ],
+            )?;
+
+            if updated == 0 {
+                Err(Error::CrateNotFound(name))
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::FunctionMetrics;
+
+    #[tokio::test]
+    async fn insert_crate_version_twice() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let depends_on = [(
+            "serde".to_string(),
+            "^1.0".to_string(),
+            String::new(),
+            "Normal".to_string(),
+            "false".to_string(),
+        )];
+
+        store
+            .insert_crate_version("painter", "0.1.0", false, &depends_on)
+            .await
+            .unwrap();
+        store
+            .insert_crate_version("painter", "0.1.0", true, &depends_on)
+            .await
+            .unwrap();
+
+        let conn = store.conn.lock().unwrap();
+        let count = |sql: &str| -> i64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+
+        assert_eq!(count("SELECT COUNT(*) FROM versions"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM depends_on"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM versions WHERE yanked = 1"), 1);
+    }
+
+    #[tokio::test]
+    async fn foreign_keys_enforced() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let functions = [FunctionRecord {
+            symbol: "_ZN7painter4main17h0123456789abcdefE".to_string(),
+            name: "painter::main".to_string(),
+            metrics: FunctionMetrics::default(),
+        }];
+
+        // The version does not exist, so nothing can refer to it
+        assert!(store
+            .insert_functions(("painter", "0.1.0"), &functions)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn set_latest_single_version() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        for version in ["0.1.0", "0.2.0", "1.0.0"] {
+            store
+                .insert_crate_version("painter", version, false, &[])
+                .await
+                .unwrap();
+        }
+
+        store.set_latest("painter", "0.2.0").await.unwrap();
+        store.set_latest("painter", "1.0.0").await.unwrap();
+
+        let conn = store.conn.lock().unwrap();
+        let latest: Vec<String> = conn
+            .prepare("SELECT version FROM versions WHERE name = ?1 AND latest = 1")
+            .unwrap()
+            .query_map(["painter"], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(latest, vec!["1.0.0".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn insert_functions_updates_metrics() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store
+            .insert_crate_version("painter", "0.1.0", false, &[])
+            .await
+            .unwrap();
+
+        // Monomorphizations share their demangled name, but are stored apart by symbol
+        let mut functions = vec![
+            FunctionRecord {
+                symbol: "_ZN7painter3map17h0123456789abcdefE".to_string(),
+                name: "painter::map".to_string(),
+                metrics: FunctionMetrics::default(),
+            },
+            FunctionRecord {
+                symbol: "_ZN7painter3map17hfedcba9876543210E".to_string(),
+                name: "painter::map".to_string(),
+                metrics: FunctionMetrics::default(),
+            },
+        ];
+        store
+            .insert_functions(("painter", "0.1.0"), &functions)
+            .await
+            .unwrap();
+        functions[0].metrics.blocks = 3;
+        store
+            .insert_functions(("painter", "0.1.0"), &functions)
+            .await
+            .unwrap();
+
+        let conn = store.conn.lock().unwrap();
+        let (count, blocks): (i64, u32) = conn
+            .query_row("SELECT COUNT(*), MAX(blocks) FROM functions", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!((count, blocks), (2, 3));
+    }
+}
@@ -0,0 +1,454 @@
+use crate::{
+    analysis::{count_unsafe_ir, demangle_symbol, load_modules_from_dir, CountUnsafeResult},
+    crate_fs::CrateEntry,
+    store::GraphStore,
+    Error,
+};
+use llvm_ir_analysis::{
+    llvm_ir::{Function, Module, Terminator},
+    ModuleAnalysis,
+};
+use std::{collections::HashMap, io::Write, path::Path, sync::Arc};
+
+/// Header of the CSV written by `export_stats_csv`, matching the columns of `write_stats_row`.
+const STATS_HEADER: &str =
+    "name,version,functions,edges,sccs,max_call_depth,recursive_functions,unsafe_functions,unsafe_total";
+
+/// Summary statistics of a crates call graph.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallGraphStats {
+    /// Number of functions in the call graph, both defined and called.
+    pub functions: usize,
+    /// Number of distinct `(caller, callee)` edges.
+    pub edges: usize,
+    /// Number of strongly connected components. Functions which are not part of a cycle are a
+    /// component on their own.
+    pub sccs: usize,
+    /// Length of the longest chain of calls, counting each strongly connected component once.
+    pub max_call_depth: usize,
+    /// Number of functions which are part of a cycle, including functions calling themselves.
+    pub recursive_functions: usize,
+}
+impl CallGraphStats {
+    /// Compute the statistics of a graph from its `(caller, callee)` edges. Functions without any
+    /// edges are passed in `functions`, and may also contain functions found in `edges`.
+    pub fn new<'a>(
+        functions: impl IntoIterator<Item = &'a str>,
+        edges: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Self {
+        let graph = CondensedGraph::new(functions, edges);
+
+        Self {
+            functions: graph.adjacency.len(),
+            edges: graph.adjacency.iter().map(Vec::len).sum(),
+            sccs: graph.sizes.len(),
+            max_call_depth: graph.depths.iter().copied().max().unwrap_or(0),
+            recursive_functions: (0..graph.adjacency.len())
+                .filter(|&v| graph.is_recursive(v))
+                .count(),
+        }
+    }
+}
+
+/// A call graph along with its strongly connected components, and the call depth of each.
+struct CondensedGraph<'a> {
+    ids: HashMap<&'a str, usize>,
+    adjacency: Vec<Vec<usize>>,
+    components: Vec<usize>,
+    /// Number of functions of each component.
+    sizes: Vec<usize>,
+    /// Length of the longest chain of calls starting at each component.
+    depths: Vec<usize>,
+}
+impl<'a> CondensedGraph<'a> {
+    fn new(
+        functions: impl IntoIterator<Item = &'a str>,
+        edges: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Self {
+        let mut ids = HashMap::<&str, usize>::new();
+        let mut adjacency = Vec::<Vec<usize>>::new();
+        let mut id = |name: &'a str, adjacency: &mut Vec<Vec<usize>>| {
+            *ids.entry(name).or_insert_with(|| {
+                adjacency.push(Vec::new());
+                adjacency.len() - 1
+            })
+        };
+
+        for function in functions {
+            id(function, &mut adjacency);
+        }
+        for (caller, callee) in edges {
+            let caller = id(caller, &mut adjacency);
+            let callee = id(callee, &mut adjacency);
+            adjacency[caller].push(callee);
+        }
+        for callees in &mut adjacency {
+            callees.sort_unstable();
+            callees.dedup();
+        }
+
+        let (components, sccs) = strongly_connected_components(&adjacency);
+
+        let mut sizes = vec![0_usize; sccs];
+        for &c in &components {
+            sizes[c] += 1;
+        }
+
+        // Components are numbered in reverse topological order, so every component a component
+        // calls into has a lower number and its depth is already known.
+        let mut members = vec![Vec::new(); sccs];
+        for (v, &c) in components.iter().enumerate() {
+            members[c].push(v);
+        }
+        let mut depths = vec![1_usize; sccs];
+        for c in 0..sccs {
+            for &v in &members[c] {
+                for &w in &adjacency[v] {
+                    if components[w] != c {
+                        depths[c] = depths[c].max(depths[components[w]] + 1);
+                    }
+                }
+            }
+        }
+
+        Self {
+            ids,
+            adjacency,
+            components,
+            sizes,
+            depths,
+        }
+    }
+
+    /// Returns true if a function is part of a cycle, including calling itself.
+    fn is_recursive(&self, v: usize) -> bool {
+        self.sizes[self.components[v]] > 1 || self.adjacency[v].contains(&v)
+    }
+
+    /// Length of the longest chain of calls starting at a function.
+    fn call_depth(&self, v: usize) -> usize {
+        self.depths[self.components[v]]
+    }
+}
+
+/// Tarjan's algorithm, without recursion so deep call chains can not overflow the stack. Returns
+/// the component of every node along with the number of components. Components are numbered in
+/// reverse topological order.
+fn strongly_connected_components(adjacency: &[Vec<usize>]) -> (Vec<usize>, usize) {
+    const UNVISITED: usize = usize::MAX;
+
+    let mut index = vec![UNVISITED; adjacency.len()];
+    let mut low = vec![0; adjacency.len()];
+    let mut on_stack = vec![false; adjacency.len()];
+    let mut components = vec![UNVISITED; adjacency.len()];
+    let mut stack = Vec::new();
+    let mut next_index = 0;
+    let mut next_component = 0;
+
+    for root in 0..adjacency.len() {
+        if index[root] != UNVISITED {
+            continue;
+        }
+
+        index[root] = next_index;
+        low[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        // Pairs of a node and the position of the next of its callees to visit.
+        let mut calls = vec![(root, 0)];
+        while let Some((v, next)) = calls.last_mut() {
+            let v = *v;
+            if let Some(&w) = adjacency[v].get(*next) {
+                *next += 1;
+                if index[w] == UNVISITED {
+                    index[w] = next_index;
+                    low[w] = next_index;
+                    next_index += 1;
+                    stack.push(w);
+                    on_stack[w] = true;
+                    calls.push((w, 0));
+                } else if on_stack[w] {
+                    low[v] = low[v].min(index[w]);
+                }
+                continue;
+            }
+
+            calls.pop();
+            if let Some(&(u, _)) = calls.last() {
+                low[u] = low[u].min(low[v]);
+            }
+            if low[v] == index[v] {
+                while let Some(w) = stack.pop() {
+                    on_stack[w] = false;
+                    components[w] = next_component;
+                    if w == v {
+                        break;
+                    }
+                }
+                next_component += 1;
+            }
+        }
+    }
+
+    (components, next_component)
+}
+
+/// Metrics of a single function defined by a crate, see `function_metrics`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionMetrics {
+    /// Number of basic blocks.
+    pub blocks: u32,
+    /// Cyclomatic complexity of the control flow graph, `edges - blocks + 2`.
+    pub cyclomatic_complexity: u32,
+    /// Whether the function is part of a cycle of calls, including calling itself.
+    pub recursive: bool,
+    /// Length of the longest chain of calls starting at the function, see
+    /// `CallGraphStats::max_call_depth`.
+    pub call_depth: u32,
+}
+
+/// A function defined by a crate along with its metrics, see `function_metrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRecord {
+    /// Mangled symbol of the function, which identifies it within the crate.
+    pub symbol: String,
+    /// Demangled name of the function, without the hash. Monomorphizations of a generic function
+    /// share their name.
+    pub name: String,
+    pub metrics: FunctionMetrics,
+}
+
+/// Number of control flow edges leaving a basic block through its terminator.
+fn successor_count(term: &Terminator) -> usize {
+    match term {
+        Terminator::Ret(_) | Terminator::Resume(_) | Terminator::Unreachable(_) => 0,
+        Terminator::Br(_) | Terminator::CatchRet(_) => 1,
+        Terminator::CondBr(_) | Terminator::Invoke(_) => 2,
+        Terminator::Switch(switch) => switch.dests.len() + 1,
+        Terminator::IndirectBr(br) => br.possible_dests.len(),
+        Terminator::CallBr(callbr) => callbr.other_labels.len() + 1,
+        Terminator::CleanupRet(ret) => usize::from(ret.unwind_dest.is_some()),
+        Terminator::CatchSwitch(switch) => {
+            switch.catch_handlers.len() + usize::from(switch.default_unwind_dest.is_some())
+        }
+    }
+}
+
+/// Cyclomatic complexity of a function, `edges - blocks + 2` over its control flow graph.
+fn cyclomatic_complexity(func: &Function) -> usize {
+    let edges: usize = func
+        .basic_blocks
+        .iter()
+        .map(|bb| successor_count(&bb.term))
+        .sum();
+
+    (edges + 2).saturating_sub(func.basic_blocks.len())
+}
+
+fn saturating_u32(n: usize) -> u32 {
+    u32::try_from(n).unwrap_or(u32::MAX)
+}
+
+/// Merge the call graphs of all modules of a crate by symbol, returning its functions and call
+/// edges. Functions called across modules are a single node of the merged graph.
+fn merged_call_graph(modules: &[Module]) -> (Vec<&str>, Vec<(&str, &str)>) {
+    let mut functions = Vec::new();
+    let mut edges = Vec::new();
+    for module in modules {
+        let analysis = ModuleAnalysis::new(module);
+        let graph = analysis.call_graph();
+        functions.extend(graph.inner().nodes());
+        edges.extend(graph.inner().all_edges().map(|(src, dst, _)| (src, dst)));
+    }
+
+    (functions, edges)
+}
+
+/// Compute the metrics of every function defined in a crates modules, along with its symbol and
+/// demangled name. The call graphs of all modules are merged with `merged_call_graph`, so recursion and
+/// call depth account for calls across modules of the crate.
+#[must_use]
+pub fn function_metrics(modules: &[Module]) -> Vec<FunctionRecord> {
+    let (functions, edges) = merged_call_graph(modules);
+    let graph = CondensedGraph::new(functions, edges);
+
+    modules
+        .iter()
+        .flat_map(|module| &module.functions)
+        .map(|func| {
+            let (recursive, call_depth) =
+                graph.ids.get(func.name.as_str()).map_or((false, 1), |&v| {
+                    (graph.is_recursive(v), graph.call_depth(v))
+                });
+
+            FunctionRecord {
+                symbol: func.name.clone(),
+                name: demangle_symbol(&func.name, false),
+                metrics: FunctionMetrics {
+                    blocks: saturating_u32(func.basic_blocks.len()),
+                    cyclomatic_complexity: saturating_u32(cyclomatic_complexity(func)),
+                    recursive,
+                    call_depth: saturating_u32(call_depth),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Compute the call graph statistics and IR-based unsafe counts of a single crates bytecode, see
+/// `count_unsafe_ir`. The call graphs of all modules of the crate are merged with
+/// `merged_call_graph`.
+fn crate_stats<P: AsRef<Path>>(crate_bc_dir: P) -> (CallGraphStats, CountUnsafeResult) {
+    let (modules, failures) = load_modules_from_dir(crate_bc_dir);
+    for (path, e) in failures {
+        log::warn!("Failed loading {}: {}", path.display(), e);
+    }
+
+    let mut unsafe_result = CountUnsafeResult::default();
+    for module in &modules {
+        unsafe_result.add(&count_unsafe_ir(module));
+    }
+
+    let (functions, edges) = merged_call_graph(&modules);
+    (CallGraphStats::new(functions, edges), unsafe_result)
+}
+
+fn write_stats_row<W: Write>(
+    out: &mut W,
+    name: &str,
+    version: &str,
+    stats: &CallGraphStats,
+    unsafe_result: &CountUnsafeResult,
+) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "{},{},{},{},{},{},{},{},{}",
+        name,
+        version,
+        stats.functions,
+        stats.edges,
+        stats.sccs,
+        stats.max_call_depth,
+        stats.recursive_functions,
+        unsafe_result.functions.unsafe_,
+        unsafe_result.total_unsafe(),
+    )
+}
+
+/// Write call graph statistics of every crate with built bytecode to a CSV file, one row per
+/// crate. Rows are written as each crate is processed, so only a single crate is held in memory.
+/// Unsafe counts are the IR-based estimates of `count_unsafe_ir`.
+///
+/// # Errors
+/// Returns `painter::Error` if the bytecode root can not be read or the output can not be written.
+/// Bytecode which fails to load is logged and skipped.
+pub fn export_stats_csv<P1: AsRef<Path>, P2: AsRef<Path>>(
+    bc_root: P1,
+    out: P2,
+) -> Result<(), Error> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+    writeln!(file, "{STATS_HEADER}")?;
+
+    for crate_bc_dir in std::fs::read_dir(&bc_root)?
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+    {
+        let file_name = crate_bc_dir.file_name();
+        let entry = match CrateEntry::try_from(file_name.to_string_lossy().as_ref()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Skipping {}: {}", crate_bc_dir.path().display(), e);
+                continue;
+            }
+        };
+
+        log::trace!("Stats: {}", entry.full_name());
+
+        let (stats, unsafe_result) = crate_stats(crate_bc_dir.path());
+        write_stats_row(
+            &mut file,
+            entry.name(),
+            entry.version(),
+            &stats,
+            &unsafe_result,
+        )?;
+        file.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Store the metrics of every function defined by each crate with built bytecode, see
+/// `function_metrics`.
+///
+/// # Errors
+/// Returns `painter::Error` if the bytecode root can not be read. Failures storing individual
+/// crates are logged and skipped.
+pub async fn export_functions_db<P: AsRef<Path>>(
+    bc_root: P,
+    db: Arc<dyn GraphStore>,
+) -> Result<(), Error> {
+    for crate_bc_dir in std::fs::read_dir(&bc_root)?
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+    {
+        let file_name = crate_bc_dir.file_name();
+        let entry = match CrateEntry::try_from(file_name.to_string_lossy().as_ref()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Skipping {}: {}", crate_bc_dir.path().display(), e);
+                continue;
+            }
+        };
+
+        log::trace!("Functions: {}", entry.full_name());
+
+        let (modules, failures) = load_modules_from_dir(crate_bc_dir.path());
+        for (path, e) in failures {
+            log::warn!("Failed loading {}: {}", path.display(), e);
+        }
+
+        let functions = function_metrics(&modules);
+        if let Err(e) = db
+            .insert_functions((entry.name(), entry.version()), &functions)
+            .await
+        {
+            log::error!("Failed storing functions of {}: {}", entry.full_name(), e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_graph_stats() {
+        let stats = CallGraphStats::new(
+            ["unused"],
+            [
+                ("main", "a"),
+                ("a", "b"),
+                ("b", "a"),
+                ("b", "c"),
+                ("c", "c"),
+                ("main", "a"),
+            ],
+        );
+
+        assert_eq!(
+            stats,
+            CallGraphStats {
+                functions: 5,
+                edges: 5,
+                sccs: 4,
+                max_call_depth: 3,
+                recursive_functions: 3,
+            }
+        );
+    }
+}
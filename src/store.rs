@@ -0,0 +1,143 @@
+use crate::{
+    analysis::{CallRecord, CountUnsafeResult},
+    db::Error,
+    stats::FunctionRecord,
+};
+
+/// A single dependency entry of a crate version, in the order of
+/// `(name, requirement, features, kind, optional)`.
+pub type Dependency = (String, String, String, String, String);
+
+/// Storage backend for the crate graph. This abstracts the operations performed during indexing,
+/// analysis and export so the graph can live in either a Neo4j instance (`db::Db`) or a local
+/// embedded database (`sqlite::SqliteStore`).
+#[async_trait::async_trait]
+pub trait GraphStore: Send + Sync {
+    /// Create the constraints and indexes of the schema. This is idempotent, and can be called on
+    /// an existing database.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn init_schema(&self) -> Result<(), Error>;
+
+    /// Insert a new function invocation from a crates version to the crate it invokes.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_invoke(
+        &self,
+        call: &CallRecord,
+        src_crate: (&str, &str),
+        dst_crate: &str,
+    ) -> Result<(), Error>;
+
+    /// Atomically insert all invocations of a crate version, so either all or none are stored.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_invokes(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error>;
+
+    /// Delete all invocations stored for a crate version.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn clear_invokes(&self, name: &str, version: &str) -> Result<(), Error>;
+
+    /// Atomically replace all invocations of a crate version with the given calls.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn reimport_crate(
+        &self,
+        src_crate: (&str, &str),
+        calls: &[CallRecord],
+    ) -> Result<(), Error>;
+
+    /// Insert a new version of a crate along with all of its dependencies, recording whether the
+    /// version has been yanked. Inserting a version which already exists must not create
+    /// duplicates, and only updates whether it has been yanked.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_crate_version(
+        &self,
+        name: &str,
+        version: &str,
+        yanked: bool,
+        depends_on: &[Dependency],
+    ) -> Result<(), Error>;
+
+    /// Insert exact version to version dependencies of a crate version, where each entry of
+    /// `resolved` is the `(name, version)` a dependency requirement resolved to.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_resolved_dependencies(
+        &self,
+        src_crate: (&str, &str),
+        resolved: &[(String, String)],
+    ) -> Result<(), Error>;
+
+    /// Returns true if a crate of the given name exists.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn crate_exists(&self, name: &str) -> Result<bool, Error>;
+
+    /// Returns true if the given crate version exists.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn version_exists(&self, name: &str, version: &str) -> Result<bool, Error>;
+
+    /// Returns true if the given crate version has any invocations stored.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn has_any_invoke(&self, name: &str, version: &str) -> Result<bool, Error>;
+
+    /// Mark the given version as the only latest version of the crate. This is atomic, so a crate
+    /// is never left without a latest version.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn set_latest(&self, name: &str, version: &str) -> Result<(), Error>;
+
+    /// Insert, or update, the metrics of a function defined by a crate version, identified by its
+    /// mangled symbol.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_function(
+        &self,
+        src_crate: (&str, &str),
+        function: &FunctionRecord,
+    ) -> Result<(), Error>;
+
+    /// Atomically insert, or update, the metrics of all given functions of a crate version, as in
+    /// `insert_function`.
+    ///
+    /// # Errors
+    /// Returns an instance of `painter::db::Error` in the event of a storage error.
+    async fn insert_functions(
+        &self,
+        src_crate: (&str, &str),
+        functions: &[FunctionRecord],
+    ) -> Result<(), Error>;
+
+    /// Store the unsafe counts of a crate version.
+    ///
+    /// # Errors
+    /// Returns `painter::db::Error::CrateNotFound` if the version does not exist, or another
+    /// instance of `painter::db::Error` in the event of a storage error.
+    async fn set_unsafe(
+        &self,
+        name: &str,
+        version: &str,
+        unsafe_result: &CountUnsafeResult,
+    ) -> Result<(), Error>;
+}